@@ -0,0 +1,156 @@
+// Generates data/nes20db.txt from the NES 2.0 XML database:
+//
+//   cargo run --example nes20db -- nes20db.xml > data/nes20db.txt
+//
+// The XML is not in the repository; it is published on the nesdev forums.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process;
+
+// the comment block at the top of the file, which documents the format
+const HEADER: &str = include_str!("../data/nes20db.txt");
+
+#[derive(Default)]
+struct Game {
+    title: String,
+    // attributes of each element of the game, by element name
+    elements: HashMap<String, HashMap<String, String>>,
+}
+
+impl Game {
+    fn attr(&self, element: &str, name: &str) -> Option<&str> {
+        return self.elements.get(element).and_then(|attrs| attrs.get(name)).map(String::as_str);
+    }
+
+    fn size(&self, element: &str) -> &str {
+        return self.attr(element, "size").unwrap_or("0");
+    }
+
+    // the game as a line of the text database, or None if it lacks a checksum or
+    // has mirroring the header cannot describe
+    fn line(&self) -> Option<String> {
+        let crc32 = self.attr("rom", "crc32")?;
+        let sha1 = self.attr("rom", "sha1").unwrap_or("-");
+        // the header's mirroring bit, clear for H and set for V; boards whose mapper
+        // controls mirroring give the bit itself, "0" or "1"
+        let mirroring = match self.attr("pcb", "mirroring") {
+            Some("H") | Some("0") | None => "H",
+            Some("V") | Some("1") => "V",
+            Some("4") => "4",
+            Some(other) => {
+                eprintln!("{}: skipped, unknown mirroring {:?}", crc32, other);
+                return None;
+            },
+        };
+        let timing = match self.attr("console", "region") {
+            Some("1") => "pal",
+            Some("2") => "multi",
+            Some("3") => "dendy",
+            _ => "ntsc",
+        };
+        let title = if self.title.is_empty() { "Unknown" } else { &self.title };
+        return Some(format!("{} {} {} {} {} {} {} {} {} {} {}",
+            crc32, sha1.to_lowercase(),
+            self.attr("pcb", "mapper").unwrap_or("0"), self.attr("pcb", "submapper").unwrap_or("0"),
+            mirroring,
+            self.size("prgram"), self.size("prgnvram"), self.size("chrram"), self.size("chrnvram"),
+            timing, title));
+    }
+}
+
+// name="value" pairs of a tag, e.g. `pcb mapper="4" submapper="0"`
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = tag;
+    while let Some(eq) = rest.find("=\"") {
+        let name = rest[..eq].split_whitespace().last().unwrap_or("").to_string();
+        let value_start = eq + 2;
+        let value_end = match rest[value_start..].find('"') {
+            Some(end) => value_start + end,
+            None => break,
+        };
+        attrs.insert(name, unescape(&rest[value_start..value_end]));
+        rest = &rest[value_end + 1..];
+    }
+    return attrs;
+}
+
+fn unescape(text: &str) -> String {
+    return text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"")
+        .replace("&apos;", "'").replace("&amp;", "&");
+}
+
+// the file name in a comment such as `NES\Licensed\Game (World).nes`, without its
+// directory or extension
+fn title(comment: &str) -> String {
+    let name = comment.trim().rsplit(['\\', '/']).next().unwrap_or("");
+    let name = name.strip_suffix(".nes").or_else(|| name.strip_suffix(".unf")).unwrap_or(name);
+    return unescape(name.trim());
+}
+
+fn convert(xml: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut game: Option<Game> = None;
+    // the comment naming a game comes just before or just inside its element
+    let mut comment = String::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if let Some(body) = rest.strip_prefix("<!--") {
+            let end = body.find("-->").unwrap_or(body.len());
+            comment = title(&body[..end]);
+            if let Some(game) = &mut game {
+                if game.title.is_empty() {
+                    game.title = comment.clone();
+                }
+            }
+            rest = &body[(end + 3).min(body.len())..];
+            continue;
+        }
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = rest[1..end].trim_end_matches('/').trim();
+        rest = &rest[end + 1..];
+
+        let name = tag.split_whitespace().next().unwrap_or("");
+        if name == "game" {
+            game = Some(Game { title: std::mem::take(&mut comment), ..Game::default() });
+        } else if name == "/game" {
+            if let Some(line) = game.take().and_then(|game| game.line()) {
+                lines.push(line);
+            }
+            comment.clear();
+        } else if let Some(game) = &mut game {
+            game.elements.insert(name.to_string(), attributes(tag));
+        }
+    }
+    return lines;
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: nes20db <nes20db.xml>");
+            process::exit(2);
+        },
+    };
+    let xml = match fs::read_to_string(&path) {
+        Ok(xml) => xml,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        },
+    };
+
+    for line in HEADER.lines().take_while(|line| line.starts_with('#')) {
+        println!("{}", line);
+    }
+    for line in convert(&xml) {
+        println!("{}", line);
+    }
+}
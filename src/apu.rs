@@ -1,7 +1,180 @@
+use crate::nes::Region;
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+// audio output rate
+const SAMPLE_RATE: u64 = 44_100;
+
 pub struct APU {
+    // $4000-$4013: pulse, triangle, noise and DMC channel registers
+    pub registers: [u8; 0x14],
+    // $4015: channel enables
+    pub status: u8,
+    // $4017: frame counter mode and IRQ inhibit
+    pub frame_counter: u8,
+    // CPU cycles into the frame counter's sequence, and its length
+    frame_cycle: u32,
+    frame_period: u32,
+    // set at the end of the four-step sequence, holding the CPU's IRQ line until
+    // acknowledged through $4015 or $4017
+    frame_irq: bool,
 
+    // CPU clock rate in Hz, which the output is resampled from
+    cpu_rate: u64,
+    // sample timing, in CPU cycles scaled by SAMPLE_RATE
+    sample_clock: u64,
+    // samples produced since they were last taken
+    samples: Vec<f32>,
 }
 
 impl APU {
+    pub fn new() -> APU {
+        let mut apu = APU {
+            registers: [0x00; 0x14],
+            status: 0,
+            frame_counter: 0,
+            frame_cycle: 0,
+            frame_period: 0,
+            frame_irq: false,
+            cpu_rate: 0,
+            sample_clock: 0,
+            samples: vec![],
+        };
+        apu.set_region(Region::Ntsc);
+        apu.power_on();
+        return apu;
+    }
+
+    // https://www.nesdev.org/wiki/CPU_power_up_state
+    pub fn power_on(&mut self) {
+        self.registers = [0x00; 0x14];
+        self.status = 0;
+        self.frame_counter = 0;
+        self.frame_cycle = 0;
+        self.frame_irq = false;
+        self.sample_clock = 0;
+        self.samples.clear();
+    }
+
+    // samples not yet taken are not part of the state
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.registers);
+        state.u8(self.status);
+        state.u8(self.frame_counter);
+        state.u64(self.frame_cycle as u64);
+        state.bool(self.frame_irq);
+        state.u64(self.sample_clock);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.registers)?;
+        self.status = state.u8()?;
+        self.frame_counter = state.u8()?;
+        let frame_cycle = state.u64()?;
+        if frame_cycle >= self.frame_period as u64 {
+            return Err(SaveStateError::Invalid);
+        }
+        self.frame_cycle = frame_cycle as u32;
+        self.frame_irq = state.bool()?;
+        self.sample_clock = state.u64()?;
+        if self.sample_clock >= self.cpu_rate {
+            return Err(SaveStateError::Invalid);
+        }
+        self.samples.clear();
+        return Ok(());
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.cpu_rate = region.master_clock_rate() / region.cpu_divider();
+        // to the nearest CPU cycle
+        // https://www.nesdev.org/wiki/APU_Frame_Counter
+        self.frame_period = match region {
+            Region::Ntsc => 29830,
+            Region::Pal => 33254,
+        };
+        self.frame_cycle %= self.frame_period;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return SAMPLE_RATE as u32;
+    }
+
+    // called once per CPU cycle
+    pub fn clock(&mut self) {
+        self.frame_cycle += 1;
+        // the four-step sequence raises the interrupt on its last step unless inhibited
+        if self.frame_cycle == self.frame_period - 1 && self.frame_counter & 0xC0 == 0 {
+            self.frame_irq = true;
+        }
+        if self.frame_cycle == self.frame_period {
+            self.frame_cycle = 0;
+        }
+
+        self.sample_clock += SAMPLE_RATE;
+        if self.sample_clock >= self.cpu_rate {
+            self.sample_clock -= self.cpu_rate;
+            let sample = self.output();
+            self.samples.push(sample);
+        }
+    }
+
+    // The mixer output in -1.0..=1.0. The channels are not synthesised yet, so
+    // this is silence.
+    fn output(&self) -> f32 {
+        return 0.0;
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        return std::mem::take(&mut self.samples);
+    }
+
+    // a reset silences every channel, $4017 keeps its last value and restarts
+    pub fn reset(&mut self) {
+        self.status = 0;
+        self.frame_cycle = 0;
+        self.frame_irq = false;
+    }
+
+    pub fn frame_irq(&self) -> bool {
+        return self.frame_irq;
+    }
+
+    // DMC playback is not emulated yet, so it never reaches the end of a sample
+    pub fn dmc_irq(&self) -> bool {
+        return false;
+    }
+
+    // $4000-$4013, $4015 and $4017
+    pub fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4013 => self.registers[(addr - 0x4000) as usize] = data,
+            0x4015 => self.status = data & 0x1F,
+            0x4017 => {
+                self.frame_counter = data & 0xC0;
+                self.frame_cycle = 0;
+                if data & 0x40 > 0 {
+                    self.frame_irq = false;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // $4015 read, which acknowledges the frame interrupt. Length counters are not
+    // emulated yet, so an enabled channel reports as still playing.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        return status;
+    }
+
+    // as read_status, leaving the frame interrupt set
+    pub fn peek_status(&self) -> u8 {
+        return (self.status & 0x1F) | ((self.frame_irq as u8) << 6);
+    }
+}
 
+impl Default for APU {
+    fn default() -> APU {
+        return APU::new();
+    }
 }
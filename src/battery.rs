@@ -0,0 +1,68 @@
+// Battery-backed cartridge RAM, kept in a .sav file beside the ROM between sessions
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub fn sav_path(rom_path: &Path) -> PathBuf {
+    return rom_path.with_extension("sav");
+}
+
+// the saved RAM, or None if the game has not been saved yet
+pub fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    return match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    };
+}
+
+// Writes a temporary file beside `path` and renames it over the old save, so a
+// crash or power loss mid-write leaves the previous save intact
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = OsString::from(path.as_os_str());
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut f = File::create(&tmp)?;
+    f.write_all(data)?;
+    f.sync_all()?;
+    drop(f);
+    return fs::rename(&tmp, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an empty directory of its own for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("emulator-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn sav_path_replaces_the_extension() {
+        assert_eq!(sav_path(Path::new("roms/game.nes")), Path::new("roms/game.sav"));
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let dir = temp_dir("save-round-trip");
+        let path = dir.join("game.sav");
+        assert!(read(&path).unwrap().is_none());
+
+        write_atomic(&path, &[0x01; 8192]).unwrap();
+        assert_eq!(read(&path).unwrap(), Some(vec![0x01; 8192]));
+
+        // an existing save is replaced whole, and nothing is left beside it
+        write_atomic(&path, &[0x02; 16]).unwrap();
+        assert_eq!(read(&path).unwrap(), Some(vec![0x02; 16]));
+        let files: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(files, ["game.sav"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+// Debugger breakpoints and watchpoints, checked by the CPU as it runs
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterruptKind {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Breakpoint {
+    // before the instruction at this address runs
+    Execute(u16),
+    // CPU bus reads and writes in start..=end, optionally only of one value
+    Read { start: u16, end: u16, value: Option<u8> },
+    Write { start: u16, end: u16, value: Option<u8> },
+    // VRAM accesses in start..=end made by the CPU through PPUDATA ($2007)
+    VramRead { start: u16, end: u16 },
+    VramWrite { start: u16, end: u16, value: Option<u8> },
+    // at the start of an interrupt sequence or BRK
+    Interrupt(InterruptKind),
+}
+
+// The event that stopped emulation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BreakpointHit {
+    // as returned by Breakpoints::add
+    pub id: usize,
+    pub breakpoint: Breakpoint,
+    // address executed or accessed; the vector for interrupts
+    pub addr: u16,
+    // value read or written, if any
+    pub value: Option<u8>,
+    // address of the instruction that was running
+    pub pc: u16,
+    pub cycle: u64,
+}
+
+fn in_range(addr: u16, start: u16, end: u16) -> bool {
+    return start <= addr && addr <= end;
+}
+
+fn value_matches(value: u8, condition: Option<u8>) -> bool {
+    return condition.is_none_or(|v| v == value);
+}
+
+pub struct Breakpoints {
+    list: Vec<(usize, Breakpoint)>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    pub fn new() -> Breakpoints {
+        return Breakpoints {
+            list: vec![],
+            next_id: 0,
+        };
+    }
+
+    // returns an id for removing the breakpoint again
+    pub fn add(&mut self, breakpoint: Breakpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.list.push((id, breakpoint));
+        return id;
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let len = self.list.len();
+        self.list.retain(|(i, _)| *i != id);
+        return self.list.len() != len;
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.list.is_empty();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(usize, Breakpoint)> {
+        return self.list.iter();
+    }
+
+    fn find<F: Fn(&Breakpoint) -> bool>(&self, f: F) -> Option<(usize, Breakpoint)> {
+        return self.list.iter().find(|(_, b)| f(b)).copied();
+    }
+
+    pub fn check_execute(&self, pc: u16) -> Option<(usize, Breakpoint)> {
+        return self.find(|b| *b == Breakpoint::Execute(pc));
+    }
+
+    pub fn check_read(&self, addr: u16, data: u8) -> Option<(usize, Breakpoint)> {
+        return self.find(|b| match *b {
+            Breakpoint::Read { start, end, value } => in_range(addr, start, end) && value_matches(data, value),
+            _ => false,
+        });
+    }
+
+    pub fn check_write(&self, addr: u16, data: u8) -> Option<(usize, Breakpoint)> {
+        return self.find(|b| match *b {
+            Breakpoint::Write { start, end, value } => in_range(addr, start, end) && value_matches(data, value),
+            _ => false,
+        });
+    }
+
+    pub fn check_vram_read(&self, addr: u16) -> Option<(usize, Breakpoint)> {
+        return self.find(|b| match *b {
+            Breakpoint::VramRead { start, end } => in_range(addr, start, end),
+            _ => false,
+        });
+    }
+
+    pub fn check_vram_write(&self, addr: u16, data: u8) -> Option<(usize, Breakpoint)> {
+        return self.find(|b| match *b {
+            Breakpoint::VramWrite { start, end, value } => in_range(addr, start, end) && value_matches(data, value),
+            _ => false,
+        });
+    }
+
+    pub fn check_interrupt(&self, kind: InterruptKind) -> Option<(usize, Breakpoint)> {
+        return self.find(|b| *b == Breakpoint::Interrupt(kind));
+    }
+}
+
+impl Default for Breakpoints {
+    fn default() -> Breakpoints {
+        return Breakpoints::new();
+    }
+}
@@ -1,6 +1,29 @@
 use crate::cartridge::Cartridge;
 use crate::apu::APU;
 use crate::ppu::PPU;
+use crate::controller::Controller;
+use crate::cpu::IrqSource;
+use crate::memory::Memory;
+use crate::nes::Region;
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+// Contents of work RAM at power on. Real consoles come up with mostly
+// unpredictable RAM, and a few games rely on (or are broken by) particular values.
+#[derive(Clone, Copy)]
+pub enum RamFill {
+    Zero,
+    Ones,
+    Random(u64),
+}
+
+// Advances a xorshift64 generator, which must not be seeded with zero, and returns
+// its new state
+pub(crate) fn xorshift64(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    return *state;
+}
 
 pub struct Bus {
     pub cpu_ram: Vec<u8>,
@@ -8,6 +31,19 @@ pub struct Bus {
 
     pub apu: APU,
     pub ppu: PPU,
+    pub controllers: [Controller; 2],
+
+    // last value on the CPU data bus, returned for addresses nothing drives
+    // https://www.nesdev.org/wiki/Open_bus_behavior
+    open_bus: u8,
+
+    region: Region,
+    // console-wide time in master clock ticks since power on, as far as the PPU has run
+    master_clock: u64,
+    // master clock at the end of the last CPU cycle
+    cpu_clock: u64,
+    // master clock at the start of the current frame's pre-render line
+    frame_start: u64,
 }
 
 impl Bus {
@@ -16,38 +52,392 @@ impl Bus {
             cpu_ram: vec![0x00; 2048],
             cartridge,
             apu,
-            ppu
+            ppu,
+            controllers: [Controller::new(), Controller::new()],
+            open_bus: 0,
+            region: Region::Ntsc,
+            master_clock: 0,
+            cpu_clock: 0,
+            frame_start: 0,
         };
     }
 
+    // CPU memory map
+    // https://www.nesdev.org/wiki/CPU_memory_map
+
     pub fn write(&mut self, addr: u16, data: u8) {
-        if self.cartridge.cpu_write(addr, data) {
-            return;
+        self.open_bus = data;
+        match addr {
+            // 2 KiB of work RAM, mirrored four times
+            0x0000..=0x1FFF => self.cpu_ram[(addr & 0x07FF) as usize] = data,
+            // eight PPU registers, mirrored every 8 bytes
+            0x2000..=0x3FFF => self.ppu.cpu_write(addr & 0x0007, data, &mut self.cartridge),
+            0x4014 => self.oam_dma(data),
+            0x4016 => {
+                // the strobe is wired to both ports
+                self.controllers[0].write(data);
+                self.controllers[1].write(data);
+            },
+            0x4000..=0x4017 => self.apu.cpu_write(addr, data),
+            // CPU test mode registers, disabled on retail consoles
+            0x4018..=0x401F => {},
+            0x4020..=0xFFFF => {
+                self.cartridge.cpu_write(addr, data);
+            },
         }
+    }
 
-        if addr <= 0x0800 {
-            self.cpu_ram[addr as usize] = data;
-        }
+    pub fn read(&mut self, addr: u16) -> u8 {
+        let data = match addr {
+            0x0000..=0x1FFF => self.cpu_ram[(addr & 0x07FF) as usize],
+            0x2000..=0x3FFF => self.ppu.cpu_read(addr & 0x0007, &mut self.cartridge),
+            // read inside the 2A03, so bit 5 is open bus and the external bus keeps its value
+            0x4015 => return (self.apu.read_status() & 0xDF) | (self.open_bus & 0x20),
+            // the controller ports only drive the low 5 bits
+            0x4016 => (self.open_bus & 0xE0) | self.controllers[0].read(),
+            0x4017 => (self.open_bus & 0xE0) | self.controllers[1].read(),
+            // the remaining APU and I/O registers are write-only
+            0x4000..=0x401F => self.open_bus,
+            0x4020..=0xFFFF => self.cartridge.cpu_read(addr).unwrap_or(self.open_bus),
+        };
+        self.open_bus = data;
+        return data;
     }
 
-    pub fn read(&self, addr: u16, read_only: bool) -> u8 {
-        let cartridge_read = self.cartridge.cpu_read(addr);
-        if !cartridge_read.is_none() {
-            return cartridge_read.unwrap();
-        }
+    // Returns what read would, without side effects on any component: PPUSTATUS
+    // keeps its vblank flag, controllers do not shift and mappers do not latch.
+    // Used by debuggers, memory viewers, the disassembler and the tracer.
+    pub fn peek(&self, addr: u16) -> u8 {
+        return match addr {
+            0x0000..=0x1FFF => self.cpu_ram[(addr & 0x07FF) as usize],
+            0x2000..=0x3FFF => self.ppu.cpu_peek(addr & 0x0007, &self.cartridge),
+            0x4015 => (self.apu.peek_status() & 0xDF) | (self.open_bus & 0x20),
+            0x4016 => (self.open_bus & 0xE0) | self.controllers[0].peek(),
+            0x4017 => (self.open_bus & 0xE0) | self.controllers[1].peek(),
+            0x4000..=0x401F => self.open_bus,
+            0x4020..=0xFFFF => self.cartridge.cpu_peek(addr).unwrap_or(self.open_bus),
+        };
+    }
 
-        if addr <= 0x0800 {
-            return self.cpu_ram[addr as usize];
+    // $4014: copies a page of CPU memory into OAM, starting at OAMADDR. The CPU is
+    // not yet suspended for the 513 cycles this takes on hardware.
+    fn oam_dma(&mut self, page: u8) {
+        for i in 0..=0xFF {
+            let data = self.read(((page as u16) << 8) | i);
+            self.ppu.cpu_write(0x0004, data, &mut self.cartridge);
         }
+    }
 
-        return 0x00;
+    pub fn power_on(&mut self, fill: RamFill) {
+        match fill {
+            RamFill::Zero => self.cpu_ram.iter_mut().for_each(|b| *b = 0x00),
+            RamFill::Ones => self.cpu_ram.iter_mut().for_each(|b| *b = 0xFF),
+            RamFill::Random(seed) => {
+                let mut state = seed | 1;
+                self.cpu_ram.iter_mut().for_each(|b| *b = (xorshift64(&mut state) >> 32) as u8);
+            },
+        }
+        self.ppu.power_on();
+        self.apu.power_on();
+        self.cartridge.power_on();
+        self.master_clock = 0;
+        self.cpu_clock = 0;
+        self.frame_start = 0;
+        self.open_bus = 0;
     }
 
+    // work RAM is left untouched by a reset
     pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
         self.cartridge.reset();
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.region as u8);
+        state.bytes(&self.cpu_ram);
+        state.u8(self.open_bus);
+        state.u64(self.master_clock);
+        state.u64(self.cpu_clock);
+        state.u64(self.frame_start);
+        self.ppu.save_state(state);
+        self.apu.save_state(state);
+        for controller in &self.controllers {
+            controller.save_state(state);
+        }
+        self.cartridge.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        if state.u8()? != self.region as u8 {
+            return Err(SaveStateError::Mismatch);
+        }
+        state.bytes(&mut self.cpu_ram)?;
+        self.open_bus = state.u8()?;
+        self.master_clock = state.u64()?;
+        self.cpu_clock = state.u64()?;
+        self.frame_start = state.u64()?;
+        // the PPU is never a dot or more behind the CPU, and the frame began in the past
+        let behind = self.cpu_clock.saturating_sub(self.master_clock);
+        if behind >= self.region.ppu_divider() || self.frame_start > self.master_clock {
+            return Err(SaveStateError::Invalid);
+        }
+        self.ppu.load_state(state)?;
+        self.apu.load_state(state)?;
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(state)?;
+        }
+        return self.cartridge.load_state(state);
+    }
+
+    pub fn region(&self) -> Region {
+        return self.region;
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
+    }
+
+    // advances the PPU by one dot
     pub fn clock_tick(&mut self) {
-        // todo
+        self.ppu.clock();
+        self.master_clock += self.region.ppu_divider();
+        if self.ppu.scanline == -1 && self.ppu.cycle == 0 {
+            self.frame_start = self.master_clock;
+        }
+    }
+
+    pub fn master_clock(&self) -> u64 {
+        return self.master_clock;
+    }
+
+    // CPU cycles elapsed since the pre-render line of the current frame began,
+    // for profiling how much of a frame a routine takes
+    pub fn cycles_since_frame_start(&self) -> u64 {
+        return (self.master_clock - self.frame_start) / self.region.cpu_divider();
+    }
+}
+
+impl Memory for Bus {
+    fn read(&mut self, addr: u16) -> u8 {
+        return Bus::read(self, addr);
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        Bus::write(self, addr, data);
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        return Bus::peek(self, addr);
+    }
+
+    fn ppu_position(&self) -> (i16, u16) {
+        return (self.ppu.scanline, self.ppu.cycle);
+    }
+
+    fn ppu_vram_address(&self) -> Option<u16> {
+        return Some(self.ppu.vram_addr & 0x3FFF);
+    }
+
+    // The PPU catches up with the CPU: three dots per cycle on NTSC, and on PAL
+    // three with a fourth every fifth cycle
+    fn tick(&mut self) {
+        self.cpu_clock += self.region.cpu_divider();
+        while self.master_clock + self.region.ppu_divider() <= self.cpu_clock {
+            self.clock_tick();
+        }
+        self.apu.clock();
+    }
+
+    fn nmi(&self) -> bool {
+        return self.ppu.nmi();
+    }
+
+    fn irq(&self) -> u8 {
+        let mut lines = 0;
+        if self.apu.frame_irq() {
+            lines |= IrqSource::FrameCounter as u8;
+        }
+        if self.apu.dmc_irq() {
+            lines |= IrqSource::Dmc as u8;
+        }
+        if self.cartridge.irq() {
+            lines |= IrqSource::Mapper as u8;
+        }
+        return lines;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::tests::ines;
+
+    fn nrom_bus() -> Bus {
+        let mut bus = Bus::new(Cartridge::from_bytes(&ines(1, 0x00, 0x00)).unwrap(), APU::new(), PPU::new());
+        bus.power_on(RamFill::Zero);
+        return bus;
+    }
+
+    #[test]
+    fn work_ram_is_mirrored() {
+        let mut bus = nrom_bus();
+        bus.write(0x0001, 0x11);
+        bus.write(0x1FFF, 0x22);
+        for base in [0x0000, 0x0800, 0x1000, 0x1800] {
+            assert_eq!(bus.read(base + 0x0001), 0x11);
+            assert_eq!(bus.read(base + 0x07FF), 0x22);
+        }
+    }
+
+    #[test]
+    fn ppu_registers_are_mirrored() {
+        // PPUADDR through $3FFE, PPUSTATUS through $200A
+        let mut bus = nrom_bus();
+        bus.write(0x3FFE, 0x21);
+        bus.write(0x3FFE, 0x08);
+        assert_eq!(bus.ppu.vram_addr, 0x2108);
+        assert_eq!(bus.read(0x200A) & 0x80, 0x80);
+        assert_eq!(bus.read(0x2002) & 0x80, 0x00);
+    }
+
+    #[test]
+    fn test_mode_registers_are_disabled() {
+        let mut bus = nrom_bus();
+        let before = (bus.cpu_ram.clone(), bus.ppu.vram_addr, bus.apu.registers);
+        // writes go nowhere, and reads see the last value on the bus
+        for addr in 0x4018..=0x401F {
+            bus.write(addr, 0x5A);
+            assert_eq!(bus.read(addr), 0x5A);
+        }
+        assert_eq!((bus.cpu_ram.clone(), bus.ppu.vram_addr, bus.apu.registers), before);
+    }
+
+    #[test]
+    fn write_only_registers_read_open_bus() {
+        let mut bus = nrom_bus();
+        bus.write(0x4000, 0x5A);
+        assert_eq!(bus.read(0x4000), 0x5A);
+        assert_eq!(bus.read(0x4014), 0x5A);
+        assert_eq!(bus.peek(0x4010), 0x5A);
+        // nothing on an NROM board answers at $5000
+        assert_eq!(bus.read(0x5000), 0x5A);
+    }
+
+    #[test]
+    fn partly_driven_registers_mix_in_open_bus() {
+        let mut bus = nrom_bus();
+        // $4015 drives all but bit 5, and its value stays inside the 2A03
+        bus.write(0x4015, 0x01);
+        bus.write(0x4000, 0xE0);
+        assert_eq!(bus.read(0x4015), 0x21);
+        assert_eq!(bus.read(0x5000), 0xE0);
+        bus.write(0x4000, 0x00);
+        assert_eq!(bus.read(0x4015), 0x01);
+
+        // the controller ports drive only their low five bits
+        bus.write(0x4000, 0xA0);
+        assert_eq!(bus.read(0x4017) & 0xE0, 0xA0);
+        assert_eq!(bus.peek(0x4016) & 0xE0, 0xA0);
+    }
+
+    #[test]
+    fn ppu_io_latch_decays() {
+        let mut bus = nrom_bus();
+        // a write to read-only PPUSTATUS still charges the latch
+        bus.write(0x2002, 0xFF);
+        bus.write(0x4000, 0x00);
+        assert_eq!(bus.read(0x2000), 0xFF);
+
+        // reading PPUSTATUS refreshes only the three bits it drives
+        bus.ppu.frame_count += 30;
+        assert_eq!(bus.read(0x2002), 0xBF);
+        bus.ppu.frame_count += 10;
+        assert_eq!(bus.read(0x2000), 0xA0);
+        bus.ppu.frame_count += 40;
+        assert_eq!(bus.read(0x2000), 0x00);
+    }
+
+    #[test]
+    fn ram_fill() {
+        let mut bus = nrom_bus();
+        bus.power_on(RamFill::Ones);
+        assert!(bus.cpu_ram.iter().all(|&b| b == 0xFF));
+        bus.power_on(RamFill::Zero);
+        assert!(bus.cpu_ram.iter().all(|&b| b == 0x00));
+
+        // the same seed gives the same contents, zero included
+        bus.power_on(RamFill::Random(0));
+        let random = bus.cpu_ram.clone();
+        assert!(random.iter().filter(|&&b| b == random[0]).count() < 64);
+        bus.power_on(RamFill::Random(0));
+        assert_eq!(bus.cpu_ram, random);
+        bus.power_on(RamFill::Random(2));
+        assert_ne!(bus.cpu_ram, random);
+
+        // a reset leaves RAM alone
+        let before = bus.cpu_ram.clone();
+        bus.reset();
+        assert_eq!(bus.cpu_ram, before);
+    }
+
+    #[test]
+    fn apu_frame_irq() {
+        let mut bus = nrom_bus();
+        bus.write(0x4017, 0x00);
+        for _ in 0..29828 {
+            bus.tick();
+        }
+        assert_eq!(Memory::irq(&bus), 0);
+        bus.tick();
+        assert_eq!(Memory::irq(&bus), IrqSource::FrameCounter as u8);
+
+        // peeking $4015 leaves it set, reading acknowledges it
+        assert_eq!(bus.peek(0x4015) & 0x40, 0x40);
+        assert_eq!(bus.read(0x4015) & 0x40, 0x40);
+        assert_eq!(Memory::irq(&bus), 0);
+
+        // the inhibit flag stops it being raised
+        bus.write(0x4017, 0x40);
+        for _ in 0..2 * 29830 {
+            bus.tick();
+        }
+        assert_eq!(Memory::irq(&bus), 0);
+    }
+
+    #[test]
+    fn peeking_ppustatus_keeps_vblank() {
+        let mut bus = nrom_bus();
+        assert_eq!(bus.ppu.status, 0xA0);
+        assert_eq!(bus.peek(0x2002) & 0xE0, 0xA0);
+        assert_eq!(bus.peek(0x2002) & 0xE0, 0xA0);
+        assert_eq!(bus.read(0x2002) & 0xE0, 0xA0);
+        assert_eq!(bus.peek(0x2002) & 0xE0, 0x20);
+    }
+
+    #[test]
+    fn peeking_ppudata_keeps_the_vram_address() {
+        let mut bus = nrom_bus();
+        bus.write(0x2006, 0x21);
+        bus.write(0x2006, 0x00);
+        bus.peek(0x2007);
+        assert_eq!(bus.ppu.vram_addr, 0x2100);
+        bus.read(0x2007);
+        assert_eq!(bus.ppu.vram_addr, 0x2101);
+    }
+
+    #[test]
+    fn peeking_a_controller_does_not_shift_it() {
+        let mut bus = nrom_bus();
+        bus.controllers[0].set_buttons(0b01);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        assert_eq!(bus.peek(0x4016) & 0x01, 1);
+        assert_eq!(bus.peek(0x4016) & 0x01, 1);
+        assert_eq!(bus.read(0x4016) & 0x01, 1);
+        assert_eq!(bus.peek(0x4016) & 0x01, 0);
+        assert_eq!(bus.read(0x4016) & 0x01, 0);
     }
 }
@@ -1,134 +1,890 @@
-use std::string::String;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Error};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
 use crate::mappers::mapper::Mapper;
 use crate::mappers::mapper_factory::create_mapper;
+use crate::nes::Region;
+use crate::patch::{self, PatchError};
+use crate::hash::{crc32, sha1};
+use crate::rom_db::RomDatabase;
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+// How the PPU's nametable addresses map onto VRAM
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mirroring {
+    // $2000 = $2400 and $2800 = $2C00, for vertical scrolling
+    Horizontal,
+    // $2000 = $2800 and $2400 = $2C00, for horizontal scrolling
+    Vertical,
+    // the cartridge supplies extra VRAM so all four nametables are distinct
+    FourScreen,
+}
+
+// Why a ROM could not be loaded
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // the file does not start with "NES\x1A"
+    BadMagic,
+    // the file ended before the trainer, PRG or CHR ROM the header declares, in bytes
+    TruncatedTrainer { expected: usize, found: usize },
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+    UnsupportedMapper(u16),
+    // a patch could not be applied to the ROM
+    Patch(PathBuf, PatchError),
+    // a header feature the emulator cannot handle yet
+    UnsupportedFeature(&'static str),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            CartridgeError::Io(e) => write!(f, "could not read ROM: {}", e),
+            CartridgeError::BadMagic => write!(f, "not an iNES ROM"),
+            CartridgeError::TruncatedTrainer { expected, found } =>
+                write!(f, "trainer is truncated: expected {} bytes, found {}", expected, found),
+            CartridgeError::TruncatedPrg { expected, found } =>
+                write!(f, "PRG ROM is truncated: expected {} bytes, found {}", expected, found),
+            CartridgeError::TruncatedChr { expected, found } =>
+                write!(f, "CHR ROM is truncated: expected {} bytes, found {}", expected, found),
+            CartridgeError::UnsupportedMapper(id) => write!(f, "mapper {} is not supported", id),
+            CartridgeError::Patch(path, e) => write!(f, "could not apply {}: {}", path.display(), e),
+            CartridgeError::UnsupportedFeature(feature) => write!(f, "{} is not supported", feature),
+        };
+    }
+}
+
+impl error::Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        return match self {
+            CartridgeError::Io(e) => Some(e),
+            CartridgeError::Patch(_, e) => Some(e),
+            _ => None,
+        };
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> CartridgeError {
+        return CartridgeError::Io(e);
+    }
+}
+
+// Reads until `buf` is full or the input ends, returning how much was read
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    return Ok(read);
+}
+
+// the trainer is loaded into PRG RAM at $7000-$71FF
+const TRAINER_SIZE: usize = 512;
+const TRAINER_OFFSET: usize = 0x1000;
 
 pub struct Cartridge {
     program_mem: Vec<u8>,
     character_mem: Vec<u8>,
+    // work RAM at $6000-$7FFF, empty when the board has none
+    prg_ram: Vec<u8>,
+    // code some ROM hacks expect in PRG RAM before reset
+    trainer: Option<Vec<u8>>,
+    // battery-backed PRG RAM written since it was last saved
+    battery_dirty: bool,
+
+    // checksums of PRG ROM followed by CHR ROM, which identify the game
+    crc32: u32,
+    sha1: [u8; 20],
+    // from the ROM database, when the game is in it
+    title: Option<String>,
+    // the database corrected the file's header
+    header_overridden: bool,
     mapper: Box<dyn Mapper>,
+    mirroring: Mirroring,
+    header: Header,
 }
 
 impl Cartridge {
-    pub fn new(rom_path: &String) -> Result<Cartridge, Error> {
-        let mut f = File::open(&rom_path)
-            .expect("ROM file should exist");
-
-        let header: INesHeader = {
-            let mut bytes = [0x00; 16];
+    // Loads a ROM file, applying an IPS, UPS or BPS patch of the same name beside it
+    // (game.ips for game.nes) if there is one
+    pub fn new<P: AsRef<Path>>(rom_path: P) -> Result<Cartridge, CartridgeError> {
+        let rom = fs::read(rom_path.as_ref())?;
+        return match patch::find_patch(rom_path.as_ref()) {
+            Some(patch_path) => {
+                let patch = fs::read(&patch_path)?;
+                let patched = patch::apply(&rom, &patch)
+                    .map_err(|e| CartridgeError::Patch(patch_path, e))?;
+                Cartridge::from_bytes(&patched)
+            },
+            None => Cartridge::from_bytes(&rom),
+        };
+    }
 
-            f.read_exact(&mut bytes[..])?;
+    // a ROM image held in memory
+    pub fn from_bytes(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+        return Cartridge::from_reader(&mut Cursor::new(data));
+    }
 
-            let mut name = [0x00; 4];
-            name.clone_from_slice(&bytes[..4]);
+    // reads a ROM image starting at the reader's current position
+    pub fn from_reader<R: Read>(reader: &mut R) -> Result<Cartridge, CartridgeError> {
+        return Cartridge::from_reader_with_database(reader, RomDatabase::embedded());
+    }
 
-            if name != [0x4E, 0x45, 0x53, 0x1A] {
-                panic!("The file supplied is not in the iNES format");
-            }
+    // Reads a ROM image, correcting its header from `database` if the game is listed
+    pub fn from_reader_with_database<R: Read>(reader: &mut R, database: &RomDatabase) -> Result<Cartridge, CartridgeError> {
+        let mut bytes = [0x00; 16];
+        // a file too short to hold a header is not an iNES file either
+        if read_fully(reader, &mut bytes[..])? < bytes.len() {
+            return Err(CartridgeError::BadMagic);
+        }
+        let mut header = Header::parse(&bytes)?;
 
-            let prg_rom_chunks = bytes[4];
-            let chr_rom_chunks = bytes[5];
-            let flags6 = bytes[6];
-            let flags7 = bytes[7];
-
-            let prg_ram_size = bytes[8];
-            let tv_system1 = bytes[9];
-            let tv_system2 = bytes[10];
-
-            INesHeader {
-                prg_rom_chunks,
-                chr_rom_chunks,
-                flags6,
-                flags7,
-                prg_ram_size,
-                tv_system1,
-                tv_system2,
+        let trainer = if header.trainer {
+            let mut trainer = vec![0x00; TRAINER_SIZE];
+            let found = read_fully(reader, &mut trainer[..])?;
+            if found < TRAINER_SIZE {
+                return Err(CartridgeError::TruncatedTrainer { expected: TRAINER_SIZE, found });
             }
+            Some(trainer)
+        } else {
+            None
         };
 
-        if header.flags6 & 0x04 > 0 {
-            // Ignore the training data if it exists
-            f.seek(SeekFrom::Current(512));
+        let mut program_mem = vec![0x00; header.prg_rom_size];
+        let found = read_fully(reader, &mut program_mem[..])?;
+        if found < program_mem.len() {
+            return Err(CartridgeError::TruncatedPrg { expected: program_mem.len(), found });
         }
 
-        let mapper_id = ((header.flags7 >> 4) << 4) | (header.flags6 >> 4);
-        let mapper = create_mapper(mapper_id, header.prg_rom_chunks, header.chr_rom_chunks);
-
-        let ines_version = (header.flags7 >> 2) & 0x03;
-        if ines_version != 1 {
-            panic!("Unknown ines file version of {} detected (Only 1 is supported). ROM possibly corrupt?", ines_version);
+        let mut character_mem = vec![0x00; header.chr_rom_size];
+        let found = read_fully(reader, &mut character_mem[..])?;
+        if found < character_mem.len() {
+            return Err(CartridgeError::TruncatedChr { expected: character_mem.len(), found });
         }
 
-        let mut program_mem = vec![0x00; header.prg_rom_chunks as usize * 16384];
-        f.read_exact(&mut program_mem[..])?;
-
-        let mut character_mem = if header.chr_rom_chunks == 0 {
-            vec![0x00; 8192]
-        } else {
-            vec![0x00; header.chr_rom_chunks as usize * 8192]
+        let rom = [&program_mem[..], &character_mem[..]].concat();
+        let crc32 = crc32(&rom);
+        let sha1 = sha1(&rom);
+        let game = database.find(crc32, &sha1);
+        let header_overridden = match game {
+            Some(game) => {
+                let corrected = game.correct(&header);
+                let overridden = corrected != header;
+                header = corrected;
+                overridden
+            },
+            None => false,
         };
-        f.read_exact(&mut character_mem[..])?;
+
+        let mapper = create_mapper(&header)?;
+
+        // no CHR ROM means the cartridge has CHR RAM instead; mappers address a
+        // whole pattern table, so a header declaring none still gets 8 KiB
+        if header.chr_rom_size == 0 {
+            let size = header.chr_ram_size + header.chr_nvram_size;
+            character_mem = vec![0x00; if size == 0 { 8192 } else { size }];
+        }
+
+        // a trainer needs RAM to be loaded into even if the header declares none
+        let mut prg_ram_size = header.prg_ram_size + header.prg_nvram_size;
+        if trainer.is_some() {
+            prg_ram_size = prg_ram_size.max(TRAINER_OFFSET + TRAINER_SIZE);
+        }
 
         return Ok(Cartridge {
             program_mem,
             character_mem,
+            prg_ram: vec![0x00; prg_ram_size],
+            trainer,
+            battery_dirty: false,
+            crc32,
+            sha1,
+            title: game.map(|game| game.title.clone()),
+            header_overridden,
             mapper,
+            mirroring: header.mirroring,
+            header,
         })
     }
 
+    // the header in effect, after any correction from the ROM database
+    pub fn header(&self) -> &Header {
+        return &self.header;
+    }
+
+    pub fn crc32(&self) -> u32 {
+        return self.crc32;
+    }
+
+    pub fn sha1(&self) -> [u8; 20] {
+        return self.sha1;
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        return self.title.as_deref();
+    }
+
+    // true when the ROM database changed something in the file's header
+    pub fn header_overridden(&self) -> bool {
+        return self.header_overridden;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        return self.mirroring;
+    }
+
+    // A ROM smaller than the window its mapper decodes repeats across it, as on a
+    // board that leaves the upper address lines unconnected.
+
     // Read and write functions return booleans which state whether
     // the cartridge's mapper has decided to take ownership of a referenced address
 
-    pub fn cpu_read(&self, addr: u16) -> Option<u8> {
+    pub fn cpu_read(&mut self, addr: u16) -> Option<u8> {
+        if let Some(data) = self.prg_ram_read(addr) {
+            return Some(data);
+        }
         let mapped_addr = self.mapper.cpu_map_read(addr);
-        return match mapped_addr {
-            Some(m_addr) => Some(self.program_mem[m_addr as usize]),
-            None => None,
-        };
+        return mapped_addr.map(|m_addr| self.program_mem[m_addr as usize % self.program_mem.len()]);
+    }
+
+    // as cpu_read, without side effects on the mapper
+    pub fn cpu_peek(&self, addr: u16) -> Option<u8> {
+        if let Some(data) = self.prg_ram_read(addr) {
+            return Some(data);
+        }
+        let mapped_addr = self.mapper.cpu_map_peek(addr);
+        return mapped_addr.map(|m_addr| self.program_mem[m_addr as usize % self.program_mem.len()]);
     }
 
     pub fn cpu_write(&mut self, addr: u16, data: u8) -> bool {
+        if !self.prg_ram.is_empty() {
+            if let Some(offset) = self.mapper.prg_ram_map_write(addr) {
+                let len = self.prg_ram.len();
+                self.prg_ram[offset as usize % len] = data;
+                self.battery_dirty |= self.header.battery;
+                return true;
+            }
+        }
         let mapped_addr = self.mapper.cpu_map_write(addr);
         return match mapped_addr {
             Some(m_addr) => {
-                self.program_mem[m_addr as usize] = data;
+                let len = self.program_mem.len();
+                self.program_mem[m_addr as usize % len] = data;
                 true
             },
             None => false,
         }
     }
 
-    pub fn ppu_read(&self, addr: u16) -> Option<u8> {
+    // PRG RAM that keeps its contents while the console is off, for saving to disk
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        if !self.header.battery || self.prg_ram.is_empty() {
+            return None;
+        }
+        return Some(&self.prg_ram);
+    }
+
+    // Restores saved RAM. A save of a different size, e.g. from another emulator,
+    // is copied as far as it fits.
+    pub fn load_battery_ram(&mut self, data: &[u8]) {
+        if !self.header.battery {
+            return;
+        }
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+        // the trainer is loaded over saved RAM at power on, and must stay there
+        self.load_trainer();
+        self.battery_dirty = false;
+    }
+
+    // true when battery RAM has been written since mark_battery_ram_saved
+    pub fn battery_ram_dirty(&self) -> bool {
+        return self.battery_dirty;
+    }
+
+    pub fn mark_battery_ram_saved(&mut self) {
+        self.battery_dirty = false;
+    }
+
+    fn prg_ram_read(&self, addr: u16) -> Option<u8> {
+        if self.prg_ram.is_empty() {
+            return None;
+        }
+        let mapped_addr = self.mapper.prg_ram_map_read(addr);
+        return mapped_addr.map(|offset| self.prg_ram[offset as usize % self.prg_ram.len()]);
+    }
+
+    pub fn ppu_read(&mut self, addr: u16) -> Option<u8> {
         let mapped_addr = self.mapper.ppu_map_read(addr);
-        return match mapped_addr {
-            Some(m_addr) => Some(self.character_mem[m_addr as usize]),
-            None => None,
-        };
+        return mapped_addr.map(|m_addr| self.character_mem[m_addr as usize % self.character_mem.len()]);
+    }
+
+    pub fn ppu_peek(&self, addr: u16) -> Option<u8> {
+        let mapped_addr = self.mapper.ppu_map_peek(addr);
+        return mapped_addr.map(|m_addr| self.character_mem[m_addr as usize % self.character_mem.len()]);
     }
 
     pub fn ppu_write(&mut self, addr: u16, data: u8) -> bool {
         let mapped_addr = self.mapper.ppu_map_write(addr);
         return match mapped_addr {
             Some(m_addr) => {
-                self.character_mem[m_addr as usize] = data;
+                let len = self.character_mem.len();
+                self.character_mem[m_addr as usize % len] = data;
                 true
             },
             None => false,
         }
     }
 
+    // CHR is saved because it may be RAM; PRG is ROM
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.character_mem);
+        state.bytes(&self.prg_ram);
+        self.mapper.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        state.bytes(&mut self.character_mem)?;
+        state.bytes(&mut self.prg_ram)?;
+        self.battery_dirty |= self.header.battery;
+        return self.mapper.load_state(state);
+    }
+
+    // Battery-backed RAM keeps its contents
+    pub fn power_on(&mut self) {
+        if !self.header.battery {
+            self.prg_ram.iter_mut().for_each(|b| *b = 0x00);
+        }
+        self.load_trainer();
+        self.mapper.reset();
+    }
+
+    fn load_trainer(&mut self) {
+        if let Some(trainer) = &self.trainer {
+            self.prg_ram[TRAINER_OFFSET..TRAINER_OFFSET + TRAINER_SIZE].copy_from_slice(trainer);
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        return self.mapper.irq();
+    }
+
     pub fn reset(&mut self) {
         self.mapper.reset();
     }
 }
 
-struct INesHeader {
-    prg_rom_chunks: u8,
-    chr_rom_chunks: u8,
-    flags6: u8,
-    flags7: u8,
-    prg_ram_size: u8,
-    tv_system1: u8,
-    tv_system2: u8,
+// Which revision of the header a ROM uses
+// https://www.nesdev.org/wiki/NES_2.0#Identification
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeaderFormat {
+    // early dumps, with bytes 7-15 unused or filled with text such as "DiskDude!"
+    Archaic,
+    INes,
+    Nes2,
+}
+
+// CPU/PPU timing the game was made for (NES 2.0 byte 12)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    // runs on both
+    MultiRegion,
+    // the Dendy famiclone
+    Dendy,
+}
+
+impl Timing {
+    // Dendy timing is not emulated; its frames are PAL length
+    pub fn region(&self) -> Region {
+        return match self {
+            Timing::Pal | Timing::Dendy => Region::Pal,
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+        };
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConsoleType {
+    Nes,
+    // Vs. System arcade board, with its PPU and hardware type (NES 2.0 byte 13)
+    VsSystem { ppu: u8, hardware: u8 },
+    PlayChoice10,
+    // one of the extended console types, such as Famiclones with a decimal mode CPU
+    // https://www.nesdev.org/wiki/NES_2.0#Extended_Console_Type
+    Extended(u8),
+}
+
+// ROM images larger than this are rejected rather than allocated
+const MAX_ROM_SIZE: usize = 64 << 20;
+
+// The iNES or NES 2.0 header at the start of a ROM image
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    // sizes in bytes; a CHR ROM size of 0 means the cartridge uses CHR RAM
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    // volatile RAM, and RAM kept by a battery (NVRAM). iNES headers only give one
+    // PRG RAM size, which counts as NVRAM when the battery flag is set.
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    // some memory is battery backed, so its contents are kept between sessions
+    pub battery: bool,
+    // 512 bytes of trainer come before the PRG ROM
+    pub trainer: bool,
+    pub timing: Timing,
+    // the region to emulate, from the timing
+    pub region: Region,
+    pub console_type: ConsoleType,
+    // ROMs after the CHR ROM, such as the PlayChoice-10 INST-ROM
+    pub misc_roms: u8,
+    // default expansion port device, e.g. the Zapper
+    // https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    pub expansion_device: u8,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8; 16]) -> Result<Header, CartridgeError> {
+        if bytes[..4] != [0x4E, 0x45, 0x53, 0x1A] {
+            return Err(CartridgeError::BadMagic);
+        }
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+
+        // flags 7 bits 2-3 are 2 for NES 2.0. Anything else is iNES, but only trusted
+        // beyond byte 6 when it is 0 and the unused bytes 12-15 are clear.
+        let format = match (flags7 >> 2) & 0x03 {
+            2 => HeaderFormat::Nes2,
+            0 if bytes[12..16].iter().all(|&b| b == 0) => HeaderFormat::INes,
+            _ => HeaderFormat::Archaic,
+        };
+
+        let mirroring = if flags6 & 0x08 > 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0x01 > 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = flags6 & 0x02 > 0;
+
+        let mut header = Header {
+            format,
+            mapper: (flags6 >> 4) as u16,
+            submapper: 0,
+            prg_rom_size: bytes[4] as usize * 16384,
+            chr_rom_size: bytes[5] as usize * 8192,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: if bytes[5] == 0 { 8192 } else { 0 },
+            chr_nvram_size: 0,
+            mirroring,
+            battery,
+            trainer: flags6 & 0x04 > 0,
+            timing: Timing::Ntsc,
+            region: Region::Ntsc,
+            console_type: ConsoleType::Nes,
+            misc_roms: 0,
+            expansion_device: 0,
+        };
+
+        // byte 8 counts 8 KiB units, with 0 meaning 8 KiB for compatibility
+        let mut prg_ram_size = 8192;
+        match format {
+            HeaderFormat::Archaic => {},
+            HeaderFormat::INes => {
+                header.mapper |= (flags7 & 0xF0) as u16;
+                header.console_type = Self::console_type(flags7, 0);
+                prg_ram_size = bytes[8].max(1) as usize * 8192;
+                if bytes[9] & 0x01 > 0 {
+                    header.timing = Timing::Pal;
+                }
+            },
+            HeaderFormat::Nes2 => {
+                header.mapper |= (flags7 & 0xF0) as u16 | ((bytes[8] & 0x0F) as u16) << 8;
+                header.submapper = bytes[8] >> 4;
+                header.prg_rom_size = Self::rom_size(bytes[4], bytes[9] & 0x0F, 16384)?;
+                header.chr_rom_size = Self::rom_size(bytes[5], bytes[9] >> 4, 8192)?;
+                header.prg_ram_size = Self::ram_size(bytes[10] & 0x0F);
+                header.prg_nvram_size = Self::ram_size(bytes[10] >> 4);
+                header.chr_ram_size = Self::ram_size(bytes[11] & 0x0F);
+                header.chr_nvram_size = Self::ram_size(bytes[11] >> 4);
+                header.timing = match bytes[12] & 0x03 {
+                    0 => Timing::Ntsc,
+                    1 => Timing::Pal,
+                    2 => Timing::MultiRegion,
+                    _ => Timing::Dendy,
+                };
+                header.console_type = Self::console_type(flags7, bytes[13]);
+                header.misc_roms = bytes[14] & 0x03;
+                header.expansion_device = bytes[15] & 0x3F;
+            },
+        }
+        if format != HeaderFormat::Nes2 {
+            if battery {
+                header.prg_nvram_size = prg_ram_size;
+            } else {
+                header.prg_ram_size = prg_ram_size;
+            }
+        }
+
+        header.region = header.timing.region();
+
+        if header.prg_rom_size == 0 {
+            return Err(CartridgeError::UnsupportedFeature("a cartridge without PRG ROM"));
+        }
+        return Ok(header);
+    }
+
+    // NES 2.0 ROM sizes: a 12-bit count of banks, or when the high nibble is $F an
+    // exponent and multiplier, 2^E * (MM * 2 + 1) bytes
+    fn rom_size(lsb: u8, msb: u8, bank_size: usize) -> Result<usize, CartridgeError> {
+        let size = if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            1usize.checked_shl(exponent).and_then(|s| s.checked_mul(multiplier))
+        } else {
+            Some((((msb as usize) << 8) | lsb as usize) * bank_size)
+        };
+        return match size {
+            Some(size) if size <= MAX_ROM_SIZE => Ok(size),
+            _ => Err(CartridgeError::UnsupportedFeature("a ROM larger than 64 MiB")),
+        };
+    }
+
+    // NES 2.0 RAM sizes are shift counts: 0 is none, otherwise 64 << count bytes
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            return 0;
+        }
+        return 64 << shift;
+    }
+
+    fn console_type(flags7: u8, byte13: u8) -> ConsoleType {
+        return match flags7 & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem { ppu: byte13 & 0x0F, hardware: byte13 >> 4 },
+            2 => ConsoleType::PlayChoice10,
+            _ => ConsoleType::Extended(byte13 & 0x0F),
+        };
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::bus::xorshift64;
+
+    // An iNES image with `prg_banks` 16 KiB banks of PRG filled with their bank
+    // number, one 8 KiB bank of CHR, and header flags 6 and 7. PRG starts at offset 16.
+    pub(crate) fn ines(prg_banks: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+        let mut rom = vec![b'N', b'E', b'S', 0x1A, prg_banks, 1, flags6, flags7];
+        rom.resize(16, 0x00);
+        for bank in 0..prg_banks {
+            rom.extend_from_slice(&[bank; 0x4000]);
+        }
+        rom.extend_from_slice(&[0xCC; 0x2000]);
+        return rom;
+    }
+
+    // switches $8000-$BFFF to the other bank whenever $BFFF is read, like the
+    // tile-fetch latches of MMC2
+    struct LatchingMapper {
+        bank: u32,
+    }
+
+    impl Mapper for LatchingMapper {
+        fn cpu_map_read(&mut self, addr: u16) -> Option<u32> {
+            let mapped = self.cpu_map_peek(addr);
+            if addr == 0xBFFF {
+                self.bank ^= 1;
+            }
+            return mapped;
+        }
+
+        fn cpu_map_peek(&self, addr: u16) -> Option<u32> {
+            if addr >= 0x8000 {
+                return Some(self.bank * 0x4000 + (addr & 0x3FFF) as u32);
+            }
+            return None;
+        }
+
+        fn cpu_map_write(&mut self, _addr: u16) -> Option<u32> {
+            return None;
+        }
+
+        fn ppu_map_read(&mut self, addr: u16) -> Option<u32> {
+            return self.ppu_map_peek(addr);
+        }
+
+        fn ppu_map_peek(&self, addr: u16) -> Option<u32> {
+            return Some(addr as u32 & 0x1FFF);
+        }
+
+        fn ppu_map_write(&mut self, _addr: u16) -> Option<u32> {
+            return None;
+        }
+
+        fn reset(&mut self) {
+            self.bank = 0;
+        }
+    }
+
+    #[test]
+    fn peeking_does_not_latch_the_mapper() {
+        let mut cartridge = Cartridge::from_bytes(&ines(2, 0x00, 0x00)).unwrap();
+        cartridge.mapper = Box::new(LatchingMapper { bank: 0 });
+
+        assert_eq!(cartridge.cpu_peek(0xBFFF), Some(0));
+        assert_eq!(cartridge.cpu_peek(0xBFFF), Some(0));
+        assert_eq!(cartridge.cpu_read(0xBFFF), Some(0));
+        assert_eq!(cartridge.cpu_peek(0x8000), Some(1));
+        assert_eq!(cartridge.cpu_read(0x8000), Some(1));
+    }
+
+    // seeded, so the inputs are the same on every run
+    fn random_bytes(state: &mut u64, len: usize) -> Vec<u8> {
+        return (0..len).map(|_| (xorshift64(state) >> 32) as u8).collect();
+    }
+
+    #[test]
+    fn random_headers_do_not_panic() {
+        let mut state = 0x2545F4914F6CDD1D;
+        for i in 0..20_000 {
+            let len = random_bytes(&mut state, 1)[0] as usize;
+            let mut data = random_bytes(&mut state, 16 + len);
+            // most inputs get the magic so parsing goes past it
+            if i % 4 != 0 {
+                data[..4].copy_from_slice(b"NES\x1A");
+            }
+            let _ = Cartridge::from_bytes(&data);
+        }
+    }
+
+    #[test]
+    fn truncated_images_are_errors() {
+        for flags6 in [0x00, 0x04] {
+            let mut rom = ines(1, flags6, 0x00);
+            if flags6 & 0x04 > 0 {
+                rom.splice(16..16, [0xAA; TRAINER_SIZE]);
+            }
+            assert!(Cartridge::from_bytes(&rom).is_ok());
+            for len in 0..rom.len() {
+                assert!(Cartridge::from_bytes(&rom[..len]).is_err(), "{} of {} bytes", len, rom.len());
+            }
+        }
+    }
+
+    // an NROM image with a trainer of 0x00, 0x01, .. 0xFF, 0x00, ..
+    pub(crate) fn with_trainer() -> Vec<u8> {
+        let mut rom = ines(1, 0x04, 0x00);
+        let trainer: Vec<u8> = (0..TRAINER_SIZE).map(|i| i as u8).collect();
+        rom.splice(16..16, trainer);
+        return rom;
+    }
+
+    #[test]
+    fn trainer_is_loaded_at_7000() {
+        let mut cartridge = Cartridge::from_bytes(&with_trainer()).unwrap();
+        cartridge.power_on();
+        for addr in 0x7000..0x7200 {
+            assert_eq!(cartridge.cpu_read(addr), Some(addr as u8), "${:04X}", addr);
+        }
+        assert_eq!(cartridge.cpu_read(0x6FFF), Some(0x00));
+        assert_eq!(cartridge.cpu_read(0x7200), Some(0x00));
+        // PRG ROM follows the trainer in the file
+        assert_eq!(cartridge.cpu_read(0x8001), Some(0x00));
+    }
+
+    #[test]
+    fn loads_from_a_reader() {
+        // the image is read in order, stopping where it ends
+        let mut data = with_trainer();
+        let len = data.len();
+        data.extend_from_slice(&[0xAA; 4]);
+        let mut reader = Cursor::new(&data);
+        let mut cartridge = Cartridge::from_reader(&mut reader).unwrap();
+        assert_eq!(reader.position(), len as u64);
+        cartridge.power_on();
+        assert_eq!(cartridge.cpu_read(0x7001), Some(0x01));
+        assert_eq!(cartridge.cpu_read(0x71FF), Some(0xFF));
+        assert_eq!(cartridge.crc32(), Cartridge::from_bytes(&data[..len]).unwrap().crc32());
+
+        // nor does it need to seek
+        let mut stream = &data[..len];
+        assert!(Cartridge::from_reader(&mut stream).is_ok());
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn truncated_trainer() {
+        let rom = with_trainer();
+        match Cartridge::from_bytes(&rom[..16 + 100]) {
+            Err(CartridgeError::TruncatedTrainer { expected: 512, found: 100 }) => {},
+            other => panic!("{:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn database_corrects_the_header() {
+        // claims mapper 2, horizontal mirroring and no battery
+        let rom = ines(1, 0x20, 0x00);
+        assert!(matches!(Cartridge::from_bytes(&rom), Err(CartridgeError::UnsupportedMapper(2))));
+
+        let contents = &rom[16..];
+        let sha1: String = sha1(contents).iter().map(|b| format!("{:02x}", b)).collect();
+        let line = format!("{:08X} {} 0 0 V 0 8192 0 0 ntsc Test Game (World)", crc32(contents), sha1);
+        let database = RomDatabase::parse(&line).unwrap();
+        let cartridge = Cartridge::from_reader_with_database(&mut Cursor::new(&rom), &database).unwrap();
+        assert!(cartridge.header_overridden());
+        assert_eq!(cartridge.title(), Some("Test Game (World)"));
+        assert_eq!(cartridge.header().mapper, 0);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert!(cartridge.header().battery);
+        assert_eq!(cartridge.battery_ram().map(|ram| ram.len()), Some(8192));
+
+        // a game the database does not list keeps its header
+        let cartridge = Cartridge::from_reader_with_database(&mut Cursor::new(ines(2, 0x01, 0x00)), &database).unwrap();
+        assert!(!cartridge.header_overridden());
+        assert_eq!(cartridge.title(), None);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+    }
+
+    // a NES 2.0 header with one bank each of PRG and CHR ROM, and the given bytes set
+    fn nes2(set: &[(usize, u8)]) -> Result<Header, CartridgeError> {
+        let mut bytes = [b'N', b'E', b'S', 0x1A, 1, 1, 0x00, 0x08, 0, 0, 0, 0, 0, 0, 0, 0];
+        for &(index, value) in set {
+            bytes[index] = value;
+        }
+        return Header::parse(&bytes);
+    }
+
+    #[test]
+    fn header_mapper_and_submapper() {
+        // the mapper's nibbles come from flags 6, flags 7 and byte 8, above the submapper
+        let header = nes2(&[(6, 0x40), (7, 0x58), (8, 0x3A)]).unwrap();
+        assert_eq!(header.format, HeaderFormat::Nes2);
+        assert_eq!((header.mapper, header.submapper), (0xA54, 3));
+
+        // iNES has 8 bits of mapper and no submapper
+        let header = Header::parse(&[b'N', b'E', b'S', 0x1A, 1, 1, 0x40, 0x50, 0x3A, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!((header.mapper, header.submapper), (0x54, 0));
+    }
+
+    #[test]
+    fn header_rom_sizes() {
+        // 12-bit bank counts
+        let header = nes2(&[(4, 0x02), (5, 0x01), (9, 0x21)]).unwrap();
+        assert_eq!(header.prg_rom_size, 0x102 * 16384);
+        assert_eq!(header.chr_rom_size, 0x201 * 8192);
+
+        // 2^E * (MM * 2 + 1) bytes: 2^10 * 3, and 2^13 * 1
+        let header = nes2(&[(4, 10 << 2 | 1), (5, 13 << 2), (9, 0xFF)]).unwrap();
+        assert_eq!(header.prg_rom_size, 3072);
+        assert_eq!(header.chr_rom_size, 8192);
+
+        // 2^63 bytes is refused rather than wrapping or allocated
+        assert!(matches!(nes2(&[(4, 63 << 2), (9, 0x0F)]), Err(CartridgeError::UnsupportedFeature(_))));
+        assert!(matches!(nes2(&[(4, 0x00)]), Err(CartridgeError::UnsupportedFeature(_))));
+    }
+
+    #[test]
+    fn header_ram_sizes() {
+        // shift counts of 64 << n bytes, with 0 meaning none
+        let header = nes2(&[(10, 0x97), (11, 0x07)]).unwrap();
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (8192, 32768));
+        assert_eq!((header.chr_ram_size, header.chr_nvram_size), (8192, 0));
+        let header = nes2(&[(5, 0)]).unwrap();
+        assert_eq!((header.chr_rom_size, header.chr_ram_size), (0, 0));
+
+        // iNES gives 8 KiB units, which are NVRAM when the battery flag is set
+        let header = Header::parse(&[b'N', b'E', b'S', 0x1A, 1, 0, 0x02, 0, 2, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (0, 16384));
+        assert_eq!((header.chr_rom_size, header.chr_ram_size), (0, 8192));
+    }
+
+    #[test]
+    fn header_timing() {
+        let timings = [
+            (Timing::Ntsc, Region::Ntsc),
+            (Timing::Pal, Region::Pal),
+            (Timing::MultiRegion, Region::Ntsc),
+            (Timing::Dendy, Region::Pal),
+        ];
+        for (value, &(timing, region)) in timings.iter().enumerate() {
+            // the upper bits of byte 12 are unused
+            let header = nes2(&[(12, 0xFC | value as u8)]).unwrap();
+            assert_eq!((header.timing, header.region), (timing, region), "{}", value);
+        }
+
+        // iNES has a PAL bit in byte 9
+        let header = Header::parse(&[b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0x01, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!((header.timing, header.region), (Timing::Pal, Region::Pal));
+    }
+
+    #[test]
+    fn header_console_type() {
+        assert_eq!(nes2(&[(13, 0x21)]).unwrap().console_type, ConsoleType::Nes);
+        assert_eq!(nes2(&[(7, 0x09), (13, 0x21)]).unwrap().console_type, ConsoleType::VsSystem { ppu: 1, hardware: 2 });
+        assert_eq!(nes2(&[(7, 0x0A)]).unwrap().console_type, ConsoleType::PlayChoice10);
+        assert_eq!(nes2(&[(7, 0x0B), (13, 0xF3)]).unwrap().console_type, ConsoleType::Extended(3));
+
+        // iNES headers do not say which Vs. System
+        let header = Header::parse(&[b'N', b'E', b'S', 0x1A, 1, 1, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(header.console_type, ConsoleType::VsSystem { ppu: 0, hardware: 0 });
+    }
+
+    #[test]
+    fn header_misc_roms() {
+        assert_eq!(nes2(&[(14, 0xFE)]).unwrap().misc_roms, 2);
+        assert_eq!(nes2(&[]).unwrap().misc_roms, 0);
+    }
+
+    #[test]
+    fn header_expansion_device() {
+        // the Zapper, with the unused top bits set
+        assert_eq!(nes2(&[(15, 0xC8)]).unwrap().expansion_device, 0x08);
+        assert_eq!(nes2(&[]).unwrap().expansion_device, 0);
+    }
+
+    #[test]
+    fn archaic_headers_ignore_bytes_7_to_15() {
+        let mut bytes = [0x00; 16];
+        bytes[..7].copy_from_slice(&[b'N', b'E', b'S', 0x1A, 2, 1, 0x11]);
+        bytes[7..].copy_from_slice(b"DiskDude!");
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!(header.format, HeaderFormat::Archaic);
+        // 'D' would otherwise add $40 to the mapper and make a Vs. System
+        assert_eq!((header.mapper, header.submapper), (1, 0));
+        assert_eq!(header.console_type, ConsoleType::Nes);
+        assert_eq!((header.prg_rom_size, header.chr_rom_size), (32768, 8192));
+        assert_eq!((header.prg_ram_size, header.prg_nvram_size), (8192, 0));
+        assert_eq!(header.timing, Timing::Ntsc);
+        assert_eq!((header.mirroring, header.trainer, header.battery), (Mirroring::Vertical, false, false));
+
+        // an otherwise iNES header with anything in bytes 12-15 is archaic too
+        let mut bytes = [b'N', b'E', b'S', 0x1A, 1, 1, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes[15] = b'!';
+        let header = Header::parse(&bytes).unwrap();
+        assert_eq!((header.format, header.mapper), (HeaderFormat::Archaic, 0));
+    }
 }
@@ -0,0 +1,93 @@
+// Standard NES joypad, read one button at a time through $4016 and $4017
+// https://www.nesdev.org/wiki/Standard_controller
+
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy)]
+pub enum Button {
+    A = 1 << 0,
+    B = 1 << 1,
+    Select = 1 << 2,
+    Start = 1 << 3,
+    Up = 1 << 4,
+    Down = 1 << 5,
+    Left = 1 << 6,
+    Right = 1 << 7,
+}
+
+pub struct Controller {
+    // currently held buttons, one bit per Button
+    buttons: u8,
+    // buttons latched by the last strobe, shifted out by reads
+    shift: u8,
+    strobe: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        return Controller {
+            buttons: 0,
+            shift: 0,
+            strobe: false,
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= button as u8;
+        } else {
+            self.buttons &= !(button as u8);
+        }
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> u8 {
+        return self.buttons;
+    }
+
+    // $4016 bit 0: while the strobe is high the shift register keeps reloading
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 0x01 > 0;
+        if self.strobe {
+            self.shift = self.buttons;
+        }
+    }
+
+    // returns the next button in bit 0; official controllers return 1 once all 8 are read
+    pub fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        if !self.strobe {
+            self.shift = (self.shift >> 1) | 0x80;
+        }
+        return bit;
+    }
+
+    // held buttons come from the player, so only the shift register is saved
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.shift);
+        state.bool(self.strobe);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.shift = state.u8()?;
+        self.strobe = state.bool()?;
+        return Ok(());
+    }
+
+    // the button the next read will return, without shifting
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            return self.buttons & 0x01;
+        }
+        return self.shift & 0x01;
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Controller {
+        return Controller::new();
+    }
+}
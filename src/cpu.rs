@@ -1,8 +1,12 @@
 // Emulates 6502 CPU
 
-use std::{ptr::null_mut, convert::TryInto, cell::RefCell, rc::Rc};
+use std::{convert::TryInto, io::Write};
 
 use crate::bus::Bus;
+use crate::memory::Memory;
+use crate::disassembler::{AddressingMode, Disassembly};
+use crate::breakpoints::{Breakpoint, BreakpointHit, Breakpoints, InterruptKind};
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
 
 enum StatusFlag{
     C = 1 << 0,
@@ -15,16 +19,74 @@ enum StatusFlag{
     N = 1 << 7,
 }
 
-#[derive(Clone)]
-struct Instruction{
+// Devices that can pull the shared IRQ line low. The line stays asserted
+// while any of them holds it.
+#[derive(Clone, Copy)]
+pub enum IrqSource{
+    FrameCounter = 1 << 0,
+    Dmc = 1 << 1,
+    Mapper = 1 << 2,
+    External = 1 << 3,
+}
+
+// The chip being emulated. The 2A03 in the NES is an NMOS 6502 with decimal mode
+// disconnected; the 65C02 (WDC) adds instructions and fixes several NMOS quirks.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Variant{
+    Ricoh2A03,
+    Nmos6502,
+    Cmos65C02,
+}
+
+// Why an execution control call returned
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopReason{
+    // the requested step finished or the run condition was met
+    Done,
+    // the cycle budget ran out first
+    CycleLimit,
+    // the CPU executed JAM or STP and will not continue until reset
+    Halted,
+    // a 65C02 WAI is asleep until an interrupt is requested
+    Waiting,
+    Breakpoint(BreakpointHit),
+}
+
+// Scanline and frame steps give up after this many cycles, in case nothing drives a PPU.
+// It is two frames on a PAL console, the longest there is.
+const FRAME_STEP_LIMIT: u64 = 2 * 33248;
+
+// How an instruction's cycles are sequenced once its opcode has been fetched
+#[derive(Clone, Copy, PartialEq)]
+enum OpKind{
+    // dummy read of the next byte, then a register-only operation
+    Implied,
+    // address cycles, then one read of the operand
+    Read,
+    // address cycles, then one write
+    Write,
+    // address cycles, read, dummy write of the unmodified value, write
+    ReadModifyWrite,
+    // branches, jumps, stack operations and interrupts sequence their own cycles
+    Control,
+}
+
+struct Instruction<M: Memory>{
     name: &'static str,
-    operation: fn(&mut CPU) -> u8,
-    addr_mode: fn(&mut CPU) -> u8,
+    operation: Operation<M>,
+    addr_mode: AddrMode<M>,
+    // nominal length, before page crossing and branch penalties
     cycles: u8,
 }
 
-type I = Instruction;
-pub struct CPU {
+type I<M> = Instruction<M>;
+// runs a cycle of the instruction's work, returning 1 once the instruction is done
+type Operation<M> = fn(&mut CPU<M>) -> u8;
+// runs a cycle of address calculation, returning 1 once the address is known
+type AddrMode<M> = fn(&mut CPU<M>) -> u8;
+
+// Generic over the memory it is attached to; on the NES that is the Bus
+pub struct CPU<M: Memory = Bus> {
     pub a: u8,
     pub x: u8,
     pub y: u8,
@@ -37,17 +99,53 @@ pub struct CPU {
     addr_abs: u16,
     addr_rel: u16,
     opcode: u8,
-    cycles: u8,
-    clock_count: u8,
-
-    lookup: Vec<Instruction>,
-
-    bus: Bus,
+    clock_count: u64,
+    variant: Variant,
+
+    // cycle of the current instruction, 0 while at an instruction boundary
+    step: u8,
+    kind: OpKind,
+    // step on which the effective address became known
+    addr_step: Option<u8>,
+    // vector of a hardware interrupt to service at the next instruction boundary
+    pending_interrupt: Option<u16>,
+    // vector of the hardware interrupt currently being serviced
+    interrupt: Option<u16>,
+    // vector read at the end of a BRK or interrupt sequence
+    vector: u16,
+
+    // interrupt lines, see poll_interrupts
+    nmi_line: bool,
+    prev_nmi_line: bool,
+    nmi_pending: bool,
+    prev_nmi_pending: bool,
+    irq_lines: u8,
+    irq_poll: bool,
+    prev_irq_poll: bool,
+
+    lookup: Vec<Instruction<M>>,
+    kinds: Vec<OpKind>,
+
+    pub bus: M,
+
+    tracer: Option<Box<dyn Write>>,
+
+    breakpoints: Breakpoints,
+    breakpoint_hit: Option<BreakpointHit>,
+    // lets execution continue from an execute breakpoint that has just fired
+    break_skip: bool,
+    // address of the current instruction's opcode
+    instr_pc: u16,
 }
 
-impl CPU {
-    pub fn new(bus: Bus) -> CPU{
-        return CPU{
+impl<M: Memory> CPU<M> {
+    // a 2A03, as found in the NES
+    pub fn new(bus: M) -> CPU<M>{
+        return CPU::with_variant(bus, Variant::Ricoh2A03);
+    }
+
+    pub fn with_variant(bus: M, variant: Variant) -> CPU<M>{
+        let mut cpu = CPU{
             a: 0,
             x: 0,
             y: 0,
@@ -59,29 +157,355 @@ impl CPU {
             addr_abs: 0,
             addr_rel: 0,
             opcode: 0,
-            cycles: 0,
             clock_count: 0,
+            variant,
+            step: 0,
+            kind: OpKind::Implied,
+            addr_step: None,
+            pending_interrupt: None,
+            interrupt: None,
+            vector: 0,
+            nmi_line: false,
+            prev_nmi_line: false,
+            nmi_pending: false,
+            prev_nmi_pending: false,
+            irq_lines: 0,
+            irq_poll: false,
+            prev_irq_poll: false,
+            kinds: vec![],
             lookup: vec![
-                I{name: "BRK", operation: CPU::BRK, addr_mode: CPU::IMM, cycles: 7}
+                // 0x0_
+                I{name: "BRK", operation: CPU::BRK, addr_mode: CPU::IMM, cycles: 7}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::IZX, cycles: 6}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::IZX, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZP0, cycles: 3}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::ZP0, cycles: 3}, I{name: "ASL", operation: CPU::ASL, addr_mode: CPU::ZP0, cycles: 5}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::ZP0, cycles: 5},
+                I{name: "PHP", operation: CPU::PHP, addr_mode: CPU::IMP, cycles: 3}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::IMM, cycles: 2}, I{name: "ASL", operation: CPU::ASL, addr_mode: CPU::IMP, cycles: 2}, I{name: "ANC", operation: CPU::ANC, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABS, cycles: 4}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::ABS, cycles: 4}, I{name: "ASL", operation: CPU::ASL, addr_mode: CPU::ABS, cycles: 6}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::ABS, cycles: 6},
+                // 0x1_
+                I{name: "BPL", operation: CPU::BPL, addr_mode: CPU::REL, cycles: 2}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::IZY, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::ZPX, cycles: 4}, I{name: "ASL", operation: CPU::ASL, addr_mode: CPU::ZPX, cycles: 6}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::ZPX, cycles: 6},
+                I{name: "CLC", operation: CPU::CLC, addr_mode: CPU::IMP, cycles: 2}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::ABY, cycles: 4}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::ABY, cycles: 7},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABX, cycles: 4}, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::ABX, cycles: 4}, I{name: "ASL", operation: CPU::ASL, addr_mode: CPU::ABX, cycles: 7}, I{name: "SLO", operation: CPU::SLO, addr_mode: CPU::ABX, cycles: 7},
+                // 0x2_
+                I{name: "JSR", operation: CPU::JSR, addr_mode: CPU::ABS, cycles: 6}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::IZX, cycles: 6}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::IZX, cycles: 8},
+                I{name: "BIT", operation: CPU::BIT, addr_mode: CPU::ZP0, cycles: 3}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::ZP0, cycles: 3}, I{name: "ROL", operation: CPU::ROL, addr_mode: CPU::ZP0, cycles: 5}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::ZP0, cycles: 5},
+                I{name: "PLP", operation: CPU::PLP, addr_mode: CPU::IMP, cycles: 4}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::IMM, cycles: 2}, I{name: "ROL", operation: CPU::ROL, addr_mode: CPU::IMP, cycles: 2}, I{name: "ANC", operation: CPU::ANC, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "BIT", operation: CPU::BIT, addr_mode: CPU::ABS, cycles: 4}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::ABS, cycles: 4}, I{name: "ROL", operation: CPU::ROL, addr_mode: CPU::ABS, cycles: 6}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::ABS, cycles: 6},
+                // 0x3_
+                I{name: "BMI", operation: CPU::BMI, addr_mode: CPU::REL, cycles: 2}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::IZY, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::ZPX, cycles: 4}, I{name: "ROL", operation: CPU::ROL, addr_mode: CPU::ZPX, cycles: 6}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::ZPX, cycles: 6},
+                I{name: "SEC", operation: CPU::SEC, addr_mode: CPU::IMP, cycles: 2}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::ABY, cycles: 4}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::ABY, cycles: 7},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABX, cycles: 4}, I{name: "AND", operation: CPU::AND, addr_mode: CPU::ABX, cycles: 4}, I{name: "ROL", operation: CPU::ROL, addr_mode: CPU::ABX, cycles: 7}, I{name: "RLA", operation: CPU::RLA, addr_mode: CPU::ABX, cycles: 7},
+                // 0x4_
+                I{name: "RTI", operation: CPU::RTI, addr_mode: CPU::IMP, cycles: 6}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::IZX, cycles: 6}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::IZX, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZP0, cycles: 3}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::ZP0, cycles: 3}, I{name: "LSR", operation: CPU::LSR, addr_mode: CPU::ZP0, cycles: 5}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::ZP0, cycles: 5},
+                I{name: "PHA", operation: CPU::PHA, addr_mode: CPU::IMP, cycles: 3}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::IMM, cycles: 2}, I{name: "LSR", operation: CPU::LSR, addr_mode: CPU::IMP, cycles: 2}, I{name: "ALR", operation: CPU::ALR, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "JMP", operation: CPU::JMP, addr_mode: CPU::ABS, cycles: 3}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::ABS, cycles: 4}, I{name: "LSR", operation: CPU::LSR, addr_mode: CPU::ABS, cycles: 6}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::ABS, cycles: 6},
+                // 0x5_
+                I{name: "BVC", operation: CPU::BVC, addr_mode: CPU::REL, cycles: 2}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::IZY, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::ZPX, cycles: 4}, I{name: "LSR", operation: CPU::LSR, addr_mode: CPU::ZPX, cycles: 6}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::ZPX, cycles: 6},
+                I{name: "CLI", operation: CPU::CLI, addr_mode: CPU::IMP, cycles: 2}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::ABY, cycles: 4}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::ABY, cycles: 7},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABX, cycles: 4}, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::ABX, cycles: 4}, I{name: "LSR", operation: CPU::LSR, addr_mode: CPU::ABX, cycles: 7}, I{name: "SRE", operation: CPU::SRE, addr_mode: CPU::ABX, cycles: 7},
+                // 0x6_
+                I{name: "RTS", operation: CPU::RTS, addr_mode: CPU::IMP, cycles: 6}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::IZX, cycles: 6}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::IZX, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZP0, cycles: 3}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::ZP0, cycles: 3}, I{name: "ROR", operation: CPU::ROR, addr_mode: CPU::ZP0, cycles: 5}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::ZP0, cycles: 5},
+                I{name: "PLA", operation: CPU::PLA, addr_mode: CPU::IMP, cycles: 4}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::IMM, cycles: 2}, I{name: "ROR", operation: CPU::ROR, addr_mode: CPU::IMP, cycles: 2}, I{name: "ARR", operation: CPU::ARR, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "JMP", operation: CPU::JMP, addr_mode: CPU::IND, cycles: 5}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::ABS, cycles: 4}, I{name: "ROR", operation: CPU::ROR, addr_mode: CPU::ABS, cycles: 6}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::ABS, cycles: 6},
+                // 0x7_
+                I{name: "BVS", operation: CPU::BVS, addr_mode: CPU::REL, cycles: 2}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::IZY, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::ZPX, cycles: 4}, I{name: "ROR", operation: CPU::ROR, addr_mode: CPU::ZPX, cycles: 6}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::ZPX, cycles: 6},
+                I{name: "SEI", operation: CPU::SEI, addr_mode: CPU::IMP, cycles: 2}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::ABY, cycles: 4}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::ABY, cycles: 7},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABX, cycles: 4}, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::ABX, cycles: 4}, I{name: "ROR", operation: CPU::ROR, addr_mode: CPU::ABX, cycles: 7}, I{name: "RRA", operation: CPU::RRA, addr_mode: CPU::ABX, cycles: 7},
+                // 0x8_
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::IZX, cycles: 6}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}, I{name: "SAX", operation: CPU::SAX, addr_mode: CPU::IZX, cycles: 6},
+                I{name: "STY", operation: CPU::STY, addr_mode: CPU::ZP0, cycles: 3}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::ZP0, cycles: 3}, I{name: "STX", operation: CPU::STX, addr_mode: CPU::ZP0, cycles: 3}, I{name: "SAX", operation: CPU::SAX, addr_mode: CPU::ZP0, cycles: 3},
+                I{name: "DEY", operation: CPU::DEY, addr_mode: CPU::IMP, cycles: 2}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}, I{name: "TXA", operation: CPU::TXA, addr_mode: CPU::IMP, cycles: 2}, I{name: "XAA", operation: CPU::XAA, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "STY", operation: CPU::STY, addr_mode: CPU::ABS, cycles: 4}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::ABS, cycles: 4}, I{name: "STX", operation: CPU::STX, addr_mode: CPU::ABS, cycles: 4}, I{name: "SAX", operation: CPU::SAX, addr_mode: CPU::ABS, cycles: 4},
+                // 0x9_
+                I{name: "BCC", operation: CPU::BCC, addr_mode: CPU::REL, cycles: 2}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::IZY, cycles: 6}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "AHX", operation: CPU::AHX, addr_mode: CPU::IZY, cycles: 6},
+                I{name: "STY", operation: CPU::STY, addr_mode: CPU::ZPX, cycles: 4}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::ZPX, cycles: 4}, I{name: "STX", operation: CPU::STX, addr_mode: CPU::ZPY, cycles: 4}, I{name: "SAX", operation: CPU::SAX, addr_mode: CPU::ZPY, cycles: 4},
+                I{name: "TYA", operation: CPU::TYA, addr_mode: CPU::IMP, cycles: 2}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::ABY, cycles: 5}, I{name: "TXS", operation: CPU::TXS, addr_mode: CPU::IMP, cycles: 2}, I{name: "TAS", operation: CPU::TAS, addr_mode: CPU::ABY, cycles: 5},
+                I{name: "SHY", operation: CPU::SHY, addr_mode: CPU::ABX, cycles: 5}, I{name: "STA", operation: CPU::STA, addr_mode: CPU::ABX, cycles: 5}, I{name: "SHX", operation: CPU::SHX, addr_mode: CPU::ABY, cycles: 5}, I{name: "AHX", operation: CPU::AHX, addr_mode: CPU::ABY, cycles: 5},
+                // 0xA_
+                I{name: "LDY", operation: CPU::LDY, addr_mode: CPU::IMM, cycles: 2}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::IZX, cycles: 6}, I{name: "LDX", operation: CPU::LDX, addr_mode: CPU::IMM, cycles: 2}, I{name: "LAX", operation: CPU::LAX, addr_mode: CPU::IZX, cycles: 6},
+                I{name: "LDY", operation: CPU::LDY, addr_mode: CPU::ZP0, cycles: 3}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::ZP0, cycles: 3}, I{name: "LDX", operation: CPU::LDX, addr_mode: CPU::ZP0, cycles: 3}, I{name: "LAX", operation: CPU::LAX, addr_mode: CPU::ZP0, cycles: 3},
+                I{name: "TAY", operation: CPU::TAY, addr_mode: CPU::IMP, cycles: 2}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::IMM, cycles: 2}, I{name: "TAX", operation: CPU::TAX, addr_mode: CPU::IMP, cycles: 2}, I{name: "LXA", operation: CPU::LXA, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "LDY", operation: CPU::LDY, addr_mode: CPU::ABS, cycles: 4}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::ABS, cycles: 4}, I{name: "LDX", operation: CPU::LDX, addr_mode: CPU::ABS, cycles: 4}, I{name: "LAX", operation: CPU::LAX, addr_mode: CPU::ABS, cycles: 4},
+                // 0xB_
+                I{name: "BCS", operation: CPU::BCS, addr_mode: CPU::REL, cycles: 2}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "LAX", operation: CPU::LAX, addr_mode: CPU::IZY, cycles: 5},
+                I{name: "LDY", operation: CPU::LDY, addr_mode: CPU::ZPX, cycles: 4}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::ZPX, cycles: 4}, I{name: "LDX", operation: CPU::LDX, addr_mode: CPU::ZPY, cycles: 4}, I{name: "LAX", operation: CPU::LAX, addr_mode: CPU::ZPY, cycles: 4},
+                I{name: "CLV", operation: CPU::CLV, addr_mode: CPU::IMP, cycles: 2}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::ABY, cycles: 4}, I{name: "TSX", operation: CPU::TSX, addr_mode: CPU::IMP, cycles: 2}, I{name: "LAS", operation: CPU::LAS, addr_mode: CPU::ABY, cycles: 4},
+                I{name: "LDY", operation: CPU::LDY, addr_mode: CPU::ABX, cycles: 4}, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::ABX, cycles: 4}, I{name: "LDX", operation: CPU::LDX, addr_mode: CPU::ABY, cycles: 4}, I{name: "LAX", operation: CPU::LAX, addr_mode: CPU::ABY, cycles: 4},
+                // 0xC_
+                I{name: "CPY", operation: CPU::CPY, addr_mode: CPU::IMM, cycles: 2}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::IZX, cycles: 6}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::IZX, cycles: 8},
+                I{name: "CPY", operation: CPU::CPY, addr_mode: CPU::ZP0, cycles: 3}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::ZP0, cycles: 3}, I{name: "DEC", operation: CPU::DEC, addr_mode: CPU::ZP0, cycles: 5}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::ZP0, cycles: 5},
+                I{name: "INY", operation: CPU::INY, addr_mode: CPU::IMP, cycles: 2}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::IMM, cycles: 2}, I{name: "DEX", operation: CPU::DEX, addr_mode: CPU::IMP, cycles: 2}, I{name: "AXS", operation: CPU::AXS, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "CPY", operation: CPU::CPY, addr_mode: CPU::ABS, cycles: 4}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::ABS, cycles: 4}, I{name: "DEC", operation: CPU::DEC, addr_mode: CPU::ABS, cycles: 6}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::ABS, cycles: 6},
+                // 0xD_
+                I{name: "BNE", operation: CPU::BNE, addr_mode: CPU::REL, cycles: 2}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::IZY, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "DEC", operation: CPU::DEC, addr_mode: CPU::ZPX, cycles: 6}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::ZPX, cycles: 6},
+                I{name: "CLD", operation: CPU::CLD, addr_mode: CPU::IMP, cycles: 2}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::ABY, cycles: 4}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::ABY, cycles: 7},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABX, cycles: 4}, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::ABX, cycles: 4}, I{name: "DEC", operation: CPU::DEC, addr_mode: CPU::ABX, cycles: 7}, I{name: "DCP", operation: CPU::DCP, addr_mode: CPU::ABX, cycles: 7},
+                // 0xE_
+                I{name: "CPX", operation: CPU::CPX, addr_mode: CPU::IMM, cycles: 2}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::IZX, cycles: 6}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::IZX, cycles: 8},
+                I{name: "CPX", operation: CPU::CPX, addr_mode: CPU::ZP0, cycles: 3}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::ZP0, cycles: 3}, I{name: "INC", operation: CPU::INC, addr_mode: CPU::ZP0, cycles: 5}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::ZP0, cycles: 5},
+                I{name: "INX", operation: CPU::INX, addr_mode: CPU::IMP, cycles: 2}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::IMM, cycles: 2}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::IMM, cycles: 2},
+                I{name: "CPX", operation: CPU::CPX, addr_mode: CPU::ABS, cycles: 4}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::ABS, cycles: 4}, I{name: "INC", operation: CPU::INC, addr_mode: CPU::ABS, cycles: 6}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::ABS, cycles: 6},
+                // 0xF_
+                I{name: "BEQ", operation: CPU::BEQ, addr_mode: CPU::REL, cycles: 2}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::IZY, cycles: 5}, I{name: "JAM", operation: CPU::JAM, addr_mode: CPU::IMP, cycles: 2}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::IZY, cycles: 8},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::ZPX, cycles: 4}, I{name: "INC", operation: CPU::INC, addr_mode: CPU::ZPX, cycles: 6}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::ZPX, cycles: 6},
+                I{name: "SED", operation: CPU::SED, addr_mode: CPU::IMP, cycles: 2}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::ABY, cycles: 4}, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 2}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::ABY, cycles: 7},
+                I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABX, cycles: 4}, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::ABX, cycles: 4}, I{name: "INC", operation: CPU::INC, addr_mode: CPU::ABX, cycles: 7}, I{name: "ISC", operation: CPU::ISC, addr_mode: CPU::ABX, cycles: 7},
             ],
             bus,
+            tracer: None,
+            breakpoints: Breakpoints::new(),
+            breakpoint_hit: None,
+            break_skip: false,
+            instr_pc: 0,
         };
+        if variant == Variant::Cmos65C02{
+            cpu.load_65c02_opcodes();
+        }
+        cpu.kinds = cpu.lookup.iter().map(Self::op_kind).collect();
+        return cpu;
+    }
+
+    // Replaces the opcodes that differ on the 65C02. Every NMOS unofficial opcode
+    // becomes a NOP, and the unused $x3 and $xB columns are single cycle NOPs.
+    fn load_65c02_opcodes(&mut self){
+        let opcodes: [(u8, Instruction<M>); 57] = [
+            (0x02, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}), (0x22, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}),
+            (0x42, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}), (0x62, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMM, cycles: 2}),
+            (0x12, I{name: "ORA", operation: CPU::ORA, addr_mode: CPU::ZPI, cycles: 5}), (0x32, I{name: "AND", operation: CPU::AND, addr_mode: CPU::ZPI, cycles: 5}),
+            (0x52, I{name: "EOR", operation: CPU::EOR, addr_mode: CPU::ZPI, cycles: 5}), (0x72, I{name: "ADC", operation: CPU::ADC, addr_mode: CPU::ZPI, cycles: 5}),
+            (0x92, I{name: "STA", operation: CPU::STA, addr_mode: CPU::ZPI, cycles: 5}), (0xB2, I{name: "LDA", operation: CPU::LDA, addr_mode: CPU::ZPI, cycles: 5}),
+            (0xD2, I{name: "CMP", operation: CPU::CMP, addr_mode: CPU::ZPI, cycles: 5}), (0xF2, I{name: "SBC", operation: CPU::SBC, addr_mode: CPU::ZPI, cycles: 5}),
+            (0x04, I{name: "TSB", operation: CPU::TSB, addr_mode: CPU::ZP0, cycles: 5}), (0x0C, I{name: "TSB", operation: CPU::TSB, addr_mode: CPU::ABS, cycles: 6}),
+            (0x14, I{name: "TRB", operation: CPU::TRB, addr_mode: CPU::ZP0, cycles: 5}), (0x1C, I{name: "TRB", operation: CPU::TRB, addr_mode: CPU::ABS, cycles: 6}),
+            (0x34, I{name: "BIT", operation: CPU::BIT, addr_mode: CPU::ZPX, cycles: 4}), (0x3C, I{name: "BIT", operation: CPU::BIT, addr_mode: CPU::ABX, cycles: 4}),
+            (0x89, I{name: "BIT", operation: CPU::BIT, addr_mode: CPU::IMM, cycles: 2}), (0x1A, I{name: "INC", operation: CPU::INC, addr_mode: CPU::IMP, cycles: 2}),
+            (0x3A, I{name: "DEC", operation: CPU::DEC, addr_mode: CPU::IMP, cycles: 2}), (0x80, I{name: "BRA", operation: CPU::BRA, addr_mode: CPU::REL, cycles: 3}),
+            (0x5A, I{name: "PHY", operation: CPU::PHY, addr_mode: CPU::IMP, cycles: 3}), (0x7A, I{name: "PLY", operation: CPU::PLY, addr_mode: CPU::IMP, cycles: 4}),
+            (0xDA, I{name: "PHX", operation: CPU::PHX, addr_mode: CPU::IMP, cycles: 3}), (0xFA, I{name: "PLX", operation: CPU::PLX, addr_mode: CPU::IMP, cycles: 4}),
+            (0x64, I{name: "STZ", operation: CPU::STZ, addr_mode: CPU::ZP0, cycles: 3}), (0x74, I{name: "STZ", operation: CPU::STZ, addr_mode: CPU::ZPX, cycles: 4}),
+            (0x9C, I{name: "STZ", operation: CPU::STZ, addr_mode: CPU::ABS, cycles: 4}), (0x9E, I{name: "STZ", operation: CPU::STZ, addr_mode: CPU::ABX, cycles: 5}),
+            (0x6C, I{name: "JMP", operation: CPU::JMP, addr_mode: CPU::IND, cycles: 6}), (0x7C, I{name: "JMP", operation: CPU::JMP, addr_mode: CPU::IAX, cycles: 6}),
+            (0x44, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZP0, cycles: 3}), (0x54, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}),
+            (0xD4, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}), (0xF4, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ZPX, cycles: 4}),
+            // $5C takes 8 cycles on hardware but is run here as a plain absolute read
+            (0x5C, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABS, cycles: 4}), (0xDC, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABS, cycles: 4}),
+            (0xFC, I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::ABS, cycles: 4}), (0xCB, I{name: "WAI", operation: CPU::WAI, addr_mode: CPU::IMP, cycles: 3}),
+            (0xDB, I{name: "STP", operation: CPU::STP, addr_mode: CPU::IMP, cycles: 3}),
+            (0x07, I{name: "RMB0", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}), (0x17, I{name: "RMB1", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0x27, I{name: "RMB2", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}), (0x37, I{name: "RMB3", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0x47, I{name: "RMB4", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}), (0x57, I{name: "RMB5", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0x67, I{name: "RMB6", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}), (0x77, I{name: "RMB7", operation: CPU::RMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0x87, I{name: "SMB0", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}), (0x97, I{name: "SMB1", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0xA7, I{name: "SMB2", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}), (0xB7, I{name: "SMB3", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0xC7, I{name: "SMB4", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}), (0xD7, I{name: "SMB5", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}),
+            (0xE7, I{name: "SMB6", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}), (0xF7, I{name: "SMB7", operation: CPU::SMB, addr_mode: CPU::ZP0, cycles: 5}),
+        ];
+        for (opcode, instr) in opcodes{
+            self.lookup[opcode as usize] = instr;
+        }
+
+        let branches = ["BBR0", "BBR1", "BBR2", "BBR3", "BBR4", "BBR5", "BBR6", "BBR7",
+            "BBS0", "BBS1", "BBS2", "BBS3", "BBS4", "BBS5", "BBS6", "BBS7"];
+        for (i, name) in branches.iter().enumerate(){
+            let operation = if i < 8 { CPU::BBR } else { CPU::BBS };
+            self.lookup[(i << 4) | 0x0F] = I{name, operation, addr_mode: CPU::ZPR, cycles: 5};
+        }
+        for hi in 0..16{
+            self.lookup[(hi << 4) | 0x03] = I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 1};
+            if hi != 0xC && hi != 0xD{
+                self.lookup[(hi << 4) | 0x0B] = I{name: "NOP", operation: CPU::NOP, addr_mode: CPU::IMP, cycles: 1};
+            }
+        }
     }
 
-    pub fn reset(&mut self) {
+    pub fn variant(&self) -> Variant{
+        return self.variant;
+    }
+
+    // cycles run since power on, counting every cycle of every instruction and interrupt
+    pub fn cycles(&self) -> u64{
+        return self.clock_count;
+    }
+
+    // Cold start. Registers are cleared and the reset sequence then runs on the next
+    // 7 cycles, leaving SP at $FD.
+    pub fn power_on(&mut self) {
         self.a = 0;
         self.x = 0;
         self.y = 0;
         self.sp = 0;
         self.pc = 0;
-        self.status = 0;
+        self.status = StatusFlag::U as u8 | StatusFlag::I as u8;
         self.fetched = 0;
         self.temp = 0;
         self.addr_abs = 0;
         self.addr_rel = 0;
         self.opcode = 0;
-        self.cycles = 0;
         self.clock_count = 0;
+        self.nmi_line = false;
+        self.prev_nmi_line = false;
+        self.irq_lines = 0;
+        self.reset();
+    }
+
+    // Pulls the reset line. A, X and Y are kept; the reset sequence on the next 7 cycles
+    // behaves like an interrupt whose stack writes are turned into reads, so SP drops
+    // by 3, I is set and PC is loaded from $FFFC.
+    pub fn reset(&mut self) {
+        self.step = 0;
+        self.addr_step = None;
+        self.interrupt = None;
+        self.pending_interrupt = Some(0xFFFC);
+        self.nmi_pending = false;
+        self.prev_nmi_pending = false;
+        self.irq_poll = false;
+        self.prev_irq_poll = false;
+    }
+
+    // tracing
+    // Every dispatched instruction is logged in the format of nestest.log:
+    // C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+
+    pub fn set_tracer(&mut self, tracer: Box<dyn Write>){
+        self.tracer = Some(tracer);
+    }
+
+    pub fn clear_tracer(&mut self) -> Option<Box<dyn Write>>{
+        return self.tracer.take();
+    }
+
+    fn trace(&mut self){
+        if self.tracer.is_none(){
+            return;
+        }
+        let line = self.trace_line();
+        let tracer = self.tracer.as_mut().unwrap();
+        if writeln!(tracer, "{}", line).is_err(){
+            // a broken sink should not bring down emulation
+            self.tracer = None;
+        }
+    }
+
+    fn trace_line(&self) -> String{
+        let instr = self.disassemble_at(self.pc);
+        let bytes: Vec<String> = instr.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let byte = instr.byte_operand();
+        let word = instr.word_operand();
+
+        // nestest.log also shows the effective address and the value stored there
+        let operand = match instr.mode {
+            AddressingMode::ZeroPage => format!("{} = {:02X}", instr.operand(), self.peek(byte as u16)),
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let index = if instr.mode == AddressingMode::ZeroPageX { self.x } else { self.y };
+                let addr = byte.wrapping_add(index);
+                format!("{} @ {:02X} = {:02X}", instr.operand(), addr, self.peek(addr as u16))
+            },
+            AddressingMode::Absolute if instr.target.is_none() => format!("{} = {:02X}", instr.operand(), self.peek(word)),
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                let index = if instr.mode == AddressingMode::AbsoluteX { self.x } else { self.y };
+                let addr = word.wrapping_add(index as u16);
+                format!("{} @ {:04X} = {:02X}", instr.operand(), addr, self.peek(addr))
+            },
+            AddressingMode::Indirect => format!("{} = {:04X}", instr.operand(), instr.target.unwrap_or(0)),
+            AddressingMode::IndirectX => {
+                let ptr = byte.wrapping_add(self.x);
+                let addr = self.peek_zp_word(ptr);
+                format!("{} @ {:02X} = {:04X} = {:02X}", instr.operand(), ptr, addr, self.peek(addr))
+            },
+            AddressingMode::IndirectY => {
+                let base = self.peek_zp_word(byte);
+                let addr = base.wrapping_add(self.y as u16);
+                format!("{} = {:04X} @ {:04X} = {:02X}", instr.operand(), base, addr, self.peek(addr))
+            },
+            _ => instr.operand(),
+        };
+
+        // nestest.log marks unofficial opcodes with '*' and spells ISC as ISB
+        let name = if instr.mnemonic == "ISC" { "ISB" } else { instr.mnemonic };
+        let marker = if instr.unofficial { '*' } else { ' ' };
+        let disassembly = format!("{} {}", name, operand);
+        let (scanline, dot) = self.bus.ppu_position();
+
+        return format!("{:04X}  {:<9}{}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            self.pc, bytes.join(" "), marker, disassembly.trim_end(),
+            self.a, self.x, self.y, self.status, self.sp,
+            scanline, dot, self.clock_count);
+    }
+
+    // disassembly
+
+    // decodes every instruction starting in start..=end without side effects
+    pub fn disassemble(&self, start: u16, end: u16) -> Vec<Disassembly>{
+        let mut instructions = vec![];
+        let mut addr = start as u32;
+        while addr <= end as u32{
+            let instr = self.disassemble_at(addr as u16);
+            addr += instr.bytes.len() as u32;
+            instructions.push(instr);
+        }
+        return instructions;
+    }
+
+    pub fn disassemble_at(&self, addr: u16) -> Disassembly{
+        let opcode = self.peek(addr);
+        let instr = &self.lookup[opcode as usize];
+        let mode = self.addressing_mode(opcode);
+        let bytes = (0..mode.size()).map(|i| self.peek(addr.wrapping_add(i))).collect();
+
+        let mut disassembly = Disassembly::new(addr, bytes, instr.name, mode, self.is_unofficial(opcode, instr.name));
+        if mode == AddressingMode::Indirect{
+            // reproduces the page wrap of JMP ($xxFF) on NMOS parts
+            let ptr = disassembly.word_operand();
+            let hi = self.peek(self.indirect_hi_ptr(ptr)) as u16;
+            disassembly.target = Some((hi << 8) | self.peek(ptr) as u16);
+        }
+        return disassembly;
+    }
+
+    fn peek_zp_word(&self, ptr: u8) -> u16{
+        return ((self.peek(ptr.wrapping_add(1) as u16) as u16) << 8) | self.peek(ptr as u16) as u16;
+    }
+
+    fn addressing_mode(&self, opcode: u8) -> AddressingMode{
+        let instr = &self.lookup[opcode as usize];
+        let modes: [(AddrMode<M>, AddressingMode); 15] = [
+            (CPU::IMP, AddressingMode::Implied), (CPU::IMM, AddressingMode::Immediate),
+            (CPU::ZP0, AddressingMode::ZeroPage), (CPU::ZPX, AddressingMode::ZeroPageX),
+            (CPU::ZPY, AddressingMode::ZeroPageY), (CPU::REL, AddressingMode::Relative),
+            (CPU::ABS, AddressingMode::Absolute), (CPU::ABX, AddressingMode::AbsoluteX),
+            (CPU::ABY, AddressingMode::AbsoluteY), (CPU::IND, AddressingMode::Indirect),
+            (CPU::IZX, AddressingMode::IndirectX), (CPU::IZY, AddressingMode::IndirectY),
+            (CPU::ZPI, AddressingMode::ZeroPageIndirect), (CPU::IAX, AddressingMode::AbsoluteIndexedIndirect),
+            (CPU::ZPR, AddressingMode::ZeroPageRelative),
+        ];
+        for (f, mode) in modes.iter(){
+            if std::ptr::fn_addr_eq(*f, instr.addr_mode){
+                // IMP also covers the accumulator forms of the shifts and rotates
+                return match (mode, instr.name) {
+                    (AddressingMode::Implied, "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC") => AddressingMode::Accumulator,
+                    _ => *mode,
+                };
+            }
+        }
+        return AddressingMode::Implied;
+    }
+
+    fn is_unofficial(&self, opcode: u8, name: &str) -> bool{
+        if self.variant == Variant::Cmos65C02{
+            // everything else is documented on the 65C02
+            return name == "NOP" && opcode != 0xEA;
+        }
+        return match name {
+            "NOP" => opcode != 0xEA,
+            "SBC" => opcode == 0xEB,
+            "ALR" | "ANC" | "ARR" | "AXS" | "DCP" | "ISC" | "JAM" | "LAS" | "LAX" | "LXA"
+                | "RLA" | "RRA" | "SAX" | "SLO" | "SRE" | "XAA" | "AHX" | "SHX" | "SHY" | "TAS" => true,
+            _ => false,
+        };
     }
 
     // helper methods
@@ -98,711 +522,2406 @@ impl CPU {
         return self.status & f as u8 > 0;
     }
 
-    fn read(&self, addr: u16) -> u8{
-        return self.bus.read(addr, false);
+    // reads without side effects, for tracing and debugging
+    fn peek(&self, addr: u16) -> u8{
+        return self.bus.peek(addr);
+    }
+
+    fn read(&mut self, addr: u16) -> u8{
+        if self.breakpoints.is_empty(){
+            return self.bus.read(addr);
+        }
+        let vram_addr = self.vram_address(addr);
+        let data = self.bus.read(addr);
+        if let Some(found) = self.breakpoints.check_read(addr, data){
+            self.hit_breakpoint(found, addr, Some(data));
+        }
+        if let Some(found) = vram_addr.and_then(|v| self.breakpoints.check_vram_read(v)){
+            self.hit_breakpoint(found, vram_addr.unwrap(), Some(data));
+        }
+        return data;
     }
 
     fn write(&mut self, addr: u16, val: u8){
+        if !self.breakpoints.is_empty(){
+            if let Some(found) = self.breakpoints.check_write(addr, val){
+                self.hit_breakpoint(found, addr, Some(val));
+            }
+            let vram_addr = self.vram_address(addr);
+            if let Some(found) = vram_addr.and_then(|v| self.breakpoints.check_vram_write(v, val)){
+                self.hit_breakpoint(found, vram_addr.unwrap(), Some(val));
+            }
+        }
         self.bus.write(addr, val);
     }
 
-    fn fetch(&mut self) -> u8{
-        // might be sketchy
-        if self.lookup[self.opcode as usize].addr_mode as usize != CPU::IMP as usize{
-            self.fetched = self.read(self.addr_abs);
+    // VRAM address about to be accessed, if `addr` is PPUDATA or one of its mirrors
+    fn vram_address(&self, addr: u16) -> Option<u16>{
+        if addr & 0xE007 != 0x2007{
+            return None;
+        }
+        return self.bus.ppu_vram_address();
+    }
+
+    // only the first breakpoint to fire before emulation stops is reported
+    fn hit_breakpoint(&mut self, found: (usize, Breakpoint), addr: u16, value: Option<u8>){
+        if self.breakpoint_hit.is_some(){
+            return;
+        }
+        let (id, breakpoint) = found;
+        self.breakpoint_hit = Some(BreakpointHit{
+            id,
+            breakpoint,
+            addr,
+            value,
+            pc: self.instr_pc,
+            cycle: self.clock_count,
+        });
+    }
+
+    // ADC and SBC only honour the D flag on parts that have decimal mode wired up
+    fn decimal_mode(&self) -> bool{
+        return self.variant != Variant::Ricoh2A03 && self.read_flag(StatusFlag::D);
+    }
+
+    // the pointer's high byte is fetched without carrying into its page, except on the 65C02
+    fn indirect_hi_ptr(&self, ptr: u16) -> u16{
+        if self.variant == Variant::Cmos65C02{
+            return ptr.wrapping_add(1);
+        }
+        return (ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF);
+    }
+
+    // true if the current instruction uses implied (or accumulator) addressing
+    fn implied(&self) -> bool{
+        return std::ptr::fn_addr_eq(self.lookup[self.opcode as usize].addr_mode, CPU::IMP as AddrMode<M>);
+    }
+
+    // the reset sequence goes through the motions of an interrupt with writes disabled
+    fn interrupt_push(&mut self, val: u8){
+        if self.interrupt == Some(0xFFFC){
+            self.read(0x0100 + self.sp as u16);
+            self.sp = self.sp.wrapping_sub(1);
+        }
+        else{
+            self.push(val);
+        }
+    }
+
+    // the dummy reads before a pull: the byte after the opcode, then the top of the
+    // stack; returns true while still in them
+    fn pull_step(&mut self) -> bool{
+        match self.step {
+            1 => { self.IMP(); },
+            2 => { self.read(0x0100 + self.sp as u16); },
+            _ => return false,
+        }
+        return true;
+    }
+
+    fn push(&mut self, val: u8){
+        self.write(0x0100 + self.sp as u16, val);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pop(&mut self) -> u8{
+        self.sp = self.sp.wrapping_add(1);
+        return self.read(0x0100 + self.sp as u16);
+    }
+
+    fn op_kind(instr: &Instruction<M>) -> OpKind{
+        return match instr.name {
+            "BCC" | "BCS" | "BEQ" | "BMI" | "BNE" | "BPL" | "BVC" | "BVS" | "BRK" | "JAM"
+                | "JMP" | "JSR" | "PHA" | "PHP" | "PLA" | "PLP" | "RTI" | "RTS"
+                | "BRA" | "PHX" | "PHY" | "PLX" | "PLY" | "WAI" | "STP" => OpKind::Control,
+            name if name.starts_with("BBR") || name.starts_with("BBS") => OpKind::Control,
+            _ if std::ptr::fn_addr_eq(instr.addr_mode, CPU::IMP as AddrMode<M>) => OpKind::Implied,
+            "STA" | "STX" | "STY" | "SAX" | "AHX" | "SHX" | "SHY" | "TAS" | "STZ" => OpKind::Write,
+            "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC"
+                | "SLO" | "RLA" | "SRE" | "RRA" | "DCP" | "ISC" | "TSB" | "TRB" => OpKind::ReadModifyWrite,
+            name if name.starts_with("RMB") || name.starts_with("SMB") => OpKind::ReadModifyWrite,
+            _ => OpKind::Read,
+        };
+    }
+
+    // runs the current instruction's addressing mode for this cycle
+    fn address(&mut self) -> u8{
+        return (self.lookup[self.opcode as usize].addr_mode)(self);
+    }
+
+    // cycles 1-3 of every branch; returns 1 once the branch has completed
+    fn branch(&mut self, condition: bool) -> u8{
+        return self.branch_from(1, condition);
+    }
+
+    // a branch whose offset is fetched on cycle `first`
+    fn branch_from(&mut self, first: u8, condition: bool) -> u8{
+        match self.step - first {
+            0 => {
+                self.REL();
+                return !condition as u8;
+            },
+            1 => {
+                // a taken branch does not poll for interrupts on this cycle, so an IRQ
+                // that arrived during the operand fetch waits for another instruction
+                if self.irq_poll && !self.prev_irq_poll{
+                    self.irq_poll = false;
+                }
+                // dummy read of the next opcode while the low byte of pc is updated
+                self.read(self.pc);
+                self.addr_abs = self.pc.wrapping_add(self.addr_rel);
+                if self.addr_abs & 0xFF00 == self.pc & 0xFF00{
+                    self.pc = self.addr_abs;
+                    return 1;
+                }
+                return 0;
+            },
+            _ => {
+                // the high byte is fixed up one cycle late
+                self.read((self.pc & 0xFF00) | (self.addr_abs & 0x00FF));
+                self.pc = self.addr_abs;
+                return 1;
+            },
         }
-        return self.fetched;
     }
     
     // address modes
+    // Each is clocked once per cycle after the opcode fetch, performs exactly the bus
+    // access of that cycle and returns 1 once addr_abs holds the effective address.
+
     fn IMP(&mut self) -> u8{
+        // dummy read of the byte following the opcode
+        self.read(self.pc);
         self.fetched = self.a;
-        return 0;
+        return 1;
     }
-    
+
     fn IMM(&mut self) -> u8{
+        // resolved during the opcode fetch without a cycle of its own
         self.addr_abs = self.pc;
-        self.pc += 1;
-        return 0;
+        self.pc = self.pc.wrapping_add(1);
+        return 1;
     }
 
     fn ZP0(&mut self) -> u8{
         self.addr_abs = self.read(self.pc) as u16;
-        self.pc += 1;
-        self.addr_abs &= 0x00FF;
-        return 0;
+        self.pc = self.pc.wrapping_add(1);
+        return 1;
     }
 
     fn ZPX(&mut self) -> u8{
-        self.addr_abs = self.read(self.pc + self.x as u16) as u16;
-        self.pc += 1;
-        self.addr_abs &= 0x00FF;
-        return 0;
+        return self.zero_page_indexed(self.x);
     }
 
     fn ZPY(&mut self) -> u8{
-        self.addr_abs = self.read(self.pc + self.y as u16) as u16;
-        self.pc += 1;
-        self.addr_abs &= 0x00FF;
-        return 0;
+        return self.zero_page_indexed(self.y);
+    }
+
+    fn zero_page_indexed(&mut self, index: u8) -> u8{
+        if self.step == 1{
+            self.addr_abs = self.read(self.pc) as u16;
+            self.pc = self.pc.wrapping_add(1);
+            return 0;
+        }
+        // dummy read of the unindexed address while the index is added
+        self.read(self.addr_abs);
+        self.addr_abs = (self.addr_abs + index as u16) & 0x00FF;
+        return 1;
     }
 
     fn REL(&mut self) -> u8{
         self.addr_rel = self.read(self.pc) as u16;
-        self.pc += 1;
-        if self.addr_abs & 0x80 > 0{
+        self.pc = self.pc.wrapping_add(1);
+        if self.addr_rel & 0x80 > 0{
             self.addr_rel |= 0xFF00;
         }
-        return 0;
+        return 1;
     }
 
     fn ABS(&mut self) -> u8{
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
-        
-        self.addr_abs = (hi << 8) | lo;
-
-        return 0;
+        if self.step == 1{
+            self.addr_abs = self.read(self.pc) as u16;
+            self.pc = self.pc.wrapping_add(1);
+            return 0;
+        }
+        self.addr_abs |= (self.read(self.pc) as u16) << 8;
+        self.pc = self.pc.wrapping_add(1);
+        return 1;
     }
 
     fn ABX(&mut self) -> u8{
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
-        
-        self.addr_abs = (hi << 8) | lo + self.x as u16;
-        if (self.addr_abs >> 8) != hi{
-            return 1;
+        if self.step == 1{
+            return self.ABS();
         }
-        return 0;
+        return self.add_index(2, self.x);
     }
 
     fn ABY(&mut self) -> u8{
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
-        
-        self.addr_abs = (hi << 8) | lo + self.y as u16;
-        if (self.addr_abs >> 8) != hi{
+        if self.step == 1{
+            return self.ABS();
+        }
+        return self.add_index(2, self.y);
+    }
+
+    // shared by ABX, ABY and IZY: on `step` the high byte of the base address is read
+    // and the index added; the following cycle is a dummy read from the address before
+    // its high byte was fixed, which reads skip unless a page boundary was crossed
+    fn add_index(&mut self, step: u8, index: u8) -> u8{
+        if self.step == step{
+            let base = if step == 2 {
+                self.ABS();
+                self.addr_abs
+            } else {
+                ((self.read((self.temp + 1) & 0x00FF) as u16) << 8) | self.addr_abs
+            };
+            self.addr_abs = base.wrapping_add(index as u16);
+            let crossed = base & 0xFF00 != self.addr_abs & 0xFF00;
+            // the 65C02 also lets the shifts and rotates skip the fix-up cycle
+            let shift = matches!(self.lookup[self.opcode as usize].name, "ASL" | "LSR" | "ROL" | "ROR");
+            let skip = self.kind == OpKind::Read || (self.variant == Variant::Cmos65C02 && shift);
+            if skip && !crossed{
+                return 1;
+            }
+            return 0;
+        }
+        if self.variant == Variant::Cmos65C02{
+            // the 65C02 rereads the last operand byte instead of an invalid address
+            self.read(self.pc.wrapping_sub(1));
             return 1;
         }
-        return 0;
+        let base = self.addr_abs.wrapping_sub(index as u16);
+        self.read((base & 0xFF00) | (self.addr_abs & 0x00FF));
+        return 1;
     }
 
     fn IND(&mut self) -> u8{
-        let lo = self.read(self.pc) as u16;
-        self.pc += 1;
-        let hi = self.read(self.pc) as u16;
-        self.pc += 1;
-
-        let ptr = (hi << 8) | lo;
-        if lo == 0x00FF{
-            self.addr_abs = ((self.read(ptr & 0xFF00) as u16) << 8) | self.read(ptr) as u16;
+        // the 65C02 spends an extra cycle avoiding the NMOS page wrap
+        let extra = (self.variant == Variant::Cmos65C02) as u8;
+        match self.step {
+            1 | 2 => {
+                self.ABS();
+                return 0;
+            },
+            3 if extra == 1 => {
+                self.read(self.pc.wrapping_sub(1));
+                return 0;
+            },
+            step if step == 3 + extra => {
+                self.temp = self.addr_abs;
+                self.addr_abs = self.read(self.temp) as u16;
+                return 0;
+            },
+            _ => {
+                let hi_ptr = self.indirect_hi_ptr(self.temp);
+                self.addr_abs |= (self.read(hi_ptr) as u16) << 8;
+                return 1;
+            },
         }
-        else{
-            self.addr_abs = ((self.read(ptr + 1) as u16) << 8) | self.read(ptr) as u16;
+    }
+
+    // (a,X), used by the 65C02's JMP
+    fn IAX(&mut self) -> u8{
+        match self.step {
+            1 | 2 => {
+                self.ABS();
+                return 0;
+            },
+            3 => {
+                // dummy read of the last operand byte while x is added
+                self.read(self.pc.wrapping_sub(1));
+                self.temp = self.addr_abs.wrapping_add(self.x as u16);
+                return 0;
+            },
+            4 => {
+                self.addr_abs = self.read(self.temp) as u16;
+                return 0;
+            },
+            _ => {
+                self.addr_abs |= (self.read(self.temp.wrapping_add(1)) as u16) << 8;
+                return 1;
+            },
         }
-        return 0;
     }
 
-    fn IZX(&mut self) -> u8{
-        let t = self.read(self.pc) as u16;
-        self.pc += 1;
+    // (zp), the 65C02's unindexed indirect mode
+    fn ZPI(&mut self) -> u8{
+        match self.step {
+            1 => {
+                self.temp = self.read(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+                return 0;
+            },
+            2 => {
+                self.addr_abs = self.read(self.temp) as u16;
+                return 0;
+            },
+            _ => {
+                self.addr_abs |= (self.read((self.temp + 1) & 0x00FF) as u16) << 8;
+                return 1;
+            },
+        }
+    }
 
-        let lo = self.read((t + self.x as u16) & 0x00FF) as u16;
-        let hi = self.read((t + self.x as u16 + 1) & 0x00FF) as u16;
+    // zp,rel of BBR and BBS: the zero page operand is read into `fetched` and the
+    // branch offset is fetched by the operation
+    fn ZPR(&mut self) -> u8{
+        match self.step {
+            1 => {
+                self.addr_abs = self.read(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+                return 0;
+            },
+            2 => {
+                self.fetched = self.read(self.addr_abs);
+                return 0;
+            },
+            _ => {
+                self.read(self.addr_abs);
+                return 1;
+            },
+        }
+    }
 
-        self.addr_abs = (hi << 8) + lo;
-        return 0;
+    fn IZX(&mut self) -> u8{
+        match self.step {
+            1 => {
+                self.temp = self.read(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+                return 0;
+            },
+            2 => {
+                // dummy read of the pointer while x is added
+                self.read(self.temp);
+                self.temp = (self.temp + self.x as u16) & 0x00FF;
+                return 0;
+            },
+            3 => {
+                self.addr_abs = self.read(self.temp) as u16;
+                return 0;
+            },
+            _ => {
+                self.addr_abs |= (self.read((self.temp + 1) & 0x00FF) as u16) << 8;
+                return 1;
+            },
+        }
     }
 
     fn IZY(&mut self) -> u8{
-        let t = self.read(self.pc) as u16;
-        self.pc += 1;
-
-        let lo = self.read((t + self.y as u16) & 0x00FF) as u16;
-        let hi = self.read((t + self.y as u16 + 1) & 0x00FF) as u16;
-
-        self.addr_abs = (hi << 8) + lo;
-        return 0;
+        match self.step {
+            1 => {
+                self.temp = self.read(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+                return 0;
+            },
+            2 => {
+                self.addr_abs = self.read(self.temp) as u16;
+                return 0;
+            },
+            _ => {
+                return self.add_index(3, self.y);
+            },
+        }
     }
 
     // operations
-    fn ADC(&mut self) -> u8{
-        self.fetch();
-        self.temp = self.a as u16 + self.fetched as u16 + self.read_flag(StatusFlag::C) as u16;
+    // shared by ADC, SBC and the unofficial opcodes built on them (RRA, ISC)
+    fn add_with_carry(&mut self, value: u8){
+        if self.decimal_mode(){
+            self.add_decimal(value);
+            return;
+        }
+        self.add_with_carry_binary(value);
+    }
+
+    fn add_with_carry_binary(&mut self, value: u8){
+        self.temp = self.a as u16 + value as u16 + self.read_flag(StatusFlag::C) as u16;
         self.set_flag(StatusFlag::C, self.temp > 255);
         self.set_flag(StatusFlag::Z, (self.temp & 0x00FF) == 0);
-        self.set_flag(StatusFlag::V, !(self.a as u16 ^ self.fetched as u16) & (self.a as u16 ^ self.temp) & 0x0080 > 0);
+        self.set_flag(StatusFlag::V, !(self.a as u16 ^ value as u16) & (self.a as u16 ^ self.temp) & 0x0080 > 0);
         self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
 
         self.a = (self.temp & 0x00FF).try_into().unwrap();
+    }
 
-        return 1;
+    fn subtract_with_carry(&mut self, value: u8){
+        if self.decimal_mode(){
+            self.subtract_decimal(value);
+            return;
+        }
+        self.add_with_carry_binary(value ^ 0xFF);
     }
 
-    fn SBC(&mut self) -> u8{
-        self.fetch();
+    // BCD addition. The NMOS part takes N and V from the result before the high digit
+    // is corrected and Z from the binary sum; the 65C02 takes N and Z from the result.
+    // http://www.6502.org/tutorials/decimal_mode.html
+    fn add_decimal(&mut self, value: u8){
+        let a = self.a as u16;
+        let value = value as u16;
+        let carry = self.read_flag(StatusFlag::C) as u16;
 
-        let value = (self.fetched ^ 0x00FF) as u16;
-        self.temp = self.a as u16 + value as u16 + self.read_flag(StatusFlag::C) as u16;
-        self.set_flag(StatusFlag::C, self.temp > 255);
-        self.set_flag(StatusFlag::Z, (self.temp & 0x00FF) == 0);
-        self.set_flag(StatusFlag::V, (self.a as u16 ^ self.temp) & (value ^ self.temp) & 0x0080 > 0);
+        let mut lo = (a & 0x0F) + (value & 0x0F) + carry;
+        if lo >= 0x0A{
+            lo = ((lo + 0x06) & 0x0F) + 0x10;
+        }
+        let mut sum = (a & 0xF0) + (value & 0xF0) + lo;
+        self.set_flag(StatusFlag::V, !(a ^ value) & (a ^ sum) & 0x0080 > 0);
+        self.set_flag(StatusFlag::N, sum & 0x0080 > 0);
+        self.set_flag(StatusFlag::Z, (a + value + carry) & 0x00FF == 0);
+        if sum >= 0xA0{
+            sum += 0x60;
+        }
+        self.set_flag(StatusFlag::C, sum > 0x00FF);
+        self.a = (sum & 0x00FF) as u8;
+
+        if self.variant == Variant::Cmos65C02{
+            self.set_flag(StatusFlag::Z, self.a == 0);
+            self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        }
+    }
+
+    // BCD subtraction. C and V always follow the binary subtraction, as do N and Z on
+    // the NMOS part.
+    fn subtract_decimal(&mut self, value: u8){
+        let a = self.a as i16;
+        let carry = self.read_flag(StatusFlag::C) as i16;
+        self.add_with_carry_binary(value ^ 0xFF);
+        let value = value as i16;
+
+        let lo = (a & 0x0F) - (value & 0x0F) + carry - 1;
+        let result = if self.variant == Variant::Cmos65C02 {
+            let mut result = a - value + carry - 1;
+            if result < 0{
+                result -= 0x60;
+            }
+            if lo < 0{
+                result -= 0x06;
+            }
+            result
+        } else {
+            let lo = if lo < 0 { ((lo - 0x06) & 0x0F) - 0x10 } else { lo };
+            let mut result = (a & 0xF0) - (value & 0xF0) + lo;
+            if result < 0{
+                result -= 0x60;
+            }
+            result
+        };
+        self.a = (result & 0x00FF) as u8;
+
+        if self.variant == Variant::Cmos65C02{
+            self.set_flag(StatusFlag::Z, self.a == 0);
+            self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        }
+    }
+
+    // 0 while the 65C02 still needs the extra cycle it takes for ADC and SBC in decimal mode
+    fn decimal_cycle(&self) -> u8{
+        return !(self.variant == Variant::Cmos65C02 && self.read_flag(StatusFlag::D)) as u8;
+    }
+
+    fn compare(&mut self, reg: u8, value: u8){
+        self.temp = (reg as u16).wrapping_sub(value as u16);
+        self.set_flag(StatusFlag::C, reg >= value);
+        self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
         self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
+    }
 
-        self.a = (self.temp & 0x00FF).try_into().unwrap();
+    fn ADC(&mut self) -> u8{
+        self.add_with_carry(self.fetched);
+        return self.decimal_cycle();
+    }
 
-        return 1;
+    fn SBC(&mut self) -> u8{
+        self.subtract_with_carry(self.fetched);
+        return self.decimal_cycle();
     }
 
     fn AND(&mut self) -> u8 {
-        self.fetch();
-        self.a = self.a & self.fetched;
+        self.a &= self.fetched;
         self.set_flag(StatusFlag::Z, self.a == 0x00);
         self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
         return 1;
     }
 
     fn ASL(&mut self) -> u8{
-        self.fetch();
         self.temp = (self.fetched as u16) << 1;
         self.set_flag(StatusFlag::C, self.temp > 0x00FF);
         self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0x00);
         self.set_flag(StatusFlag::N, self.temp & 0x80 > 0);
         let ret = (self.temp & 0x00FF) as u8;
-        if self.lookup[self.opcode as usize].addr_mode as usize != CPU::IMP as usize{
+        if self.implied(){
             self.a = ret;
         }
         else{
             self.write(self.addr_abs, ret);
         }
-        return 0;
+        return 1;
     }
 
     fn BCC(&mut self) -> u8{
-        if !self.read_flag(StatusFlag::C){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(!self.read_flag(StatusFlag::C));
     }
 
     fn BCS(&mut self) -> u8{
-        if self.read_flag(StatusFlag::C){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(self.read_flag(StatusFlag::C));
     }
 
     fn BEQ(&mut self) -> u8{
-        if self.read_flag(StatusFlag::Z){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(self.read_flag(StatusFlag::Z));
     }
 
     fn BIT(&mut self) -> u8 {
-        self.fetch();
         self.temp = (self.a & self.fetched) as u16;
         self.set_flag(StatusFlag::Z, self.temp == 0x00);
-        self.set_flag(StatusFlag::N, self.temp & (1<<7) > 0);
-        self.set_flag(StatusFlag::V, self.temp & (1<<6) > 0);
-        return 0;
+        if self.opcode == 0x89{
+            // the 65C02's BIT #imm only affects Z
+            return 1;
+        }
+        self.set_flag(StatusFlag::N, self.fetched & (1<<7) > 0);
+        self.set_flag(StatusFlag::V, self.fetched & (1<<6) > 0);
+        return 1;
     }
 
     fn BMI(&mut self) -> u8{
-        if self.read_flag(StatusFlag::N){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(self.read_flag(StatusFlag::N));
     }
 
     fn BNE(&mut self) -> u8{
-        if !self.read_flag(StatusFlag::Z){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(!self.read_flag(StatusFlag::Z));
     }
 
     fn BPL(&mut self) -> u8{
-        if !self.read_flag(StatusFlag::N){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(!self.read_flag(StatusFlag::N));
     }
 
+    // also sequences NMI, IRQ and reset, which are injected as a BRK with their own vector
     fn BRK(&mut self) -> u8{
-        self.pc += 1;
-
-        self.set_flag(StatusFlag::I, true);
-        self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-        self.sp -= 1;
-        self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF).try_into().unwrap());
-        self.sp -= 1;
-
-        self.set_flag(StatusFlag::B, true);
-        self.write(0x0100 + self.sp as u16, self.status);
-        self.sp -= 1;
-        self.set_flag(StatusFlag::B, false);
-
-        self.pc = self.read(0xFFFE) as u16 | ((self.read(0xFFFF) as u16) << 8);
-
+        match self.step {
+            1 => {
+                // BRK skips a padding byte, a hardware interrupt leaves pc alone
+                self.read(self.pc);
+                if self.interrupt.is_none(){
+                    self.pc = self.pc.wrapping_add(1);
+                }
+                self.vector = self.interrupt.unwrap_or(0xFFFE);
+            },
+            2 => self.interrupt_push(((self.pc >> 8) & 0x00FF).try_into().unwrap()),
+            3 => self.interrupt_push((self.pc & 0x00FF).try_into().unwrap()),
+            4 => {
+                // an NMI arriving before this point hijacks the sequence: the vector is
+                // switched to $FFFA but a BRK still pushes the B flag
+                if self.nmi_pending && self.vector != 0xFFFC{
+                    self.nmi_pending = false;
+                    self.vector = 0xFFFA;
+                }
+                let b = if self.interrupt.is_none() { StatusFlag::B as u8 } else { 0 };
+                self.interrupt_push(self.status | b | StatusFlag::U as u8);
+                self.set_flag(StatusFlag::I, true);
+                if self.variant == Variant::Cmos65C02{
+                    self.set_flag(StatusFlag::D, false);
+                }
+            },
+            5 => self.pc = self.read(self.vector) as u16,
+            _ => {
+                self.pc |= (self.read(self.vector + 1) as u16) << 8;
+                self.interrupt = None;
+                return 1;
+            },
+        }
         return 0;
     }
 
     fn BVC(&mut self) -> u8{
-        if !self.read_flag(StatusFlag::V){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(!self.read_flag(StatusFlag::V));
     }
 
     fn BVS(&mut self) -> u8{
-        if self.read_flag(StatusFlag::V){
-            self.cycles += 1;
-            self.addr_abs = self.pc + self.addr_rel;
-            if self.addr_abs & 0xFF00 != self.pc & 0xFF00{
-                self.cycles += 1;
-            }
-            self.pc = self.addr_abs;
-        }
-        return 0;
+        return self.branch(self.read_flag(StatusFlag::V));
     }
 
     fn CLC(&mut self) -> u8{
         self.set_flag(StatusFlag::C, false);
-        return 0;
+        return 1;
     }
 
     fn CLD(&mut self) -> u8{
         self.set_flag(StatusFlag::D, false);
-        return 0;
+        return 1;
     }
 
     fn CLI(&mut self) -> u8{
         self.set_flag(StatusFlag::I, false);
-        return 0;
+        return 1;
     }
 
     fn CLV(&mut self) -> u8{
         self.set_flag(StatusFlag::V, false);
-        return 0;
+        return 1;
     }
 
     fn CMP(&mut self) -> u8{
-        self.fetch();
-        self.temp = self.a as u16 - self.fetched as u16;
-        self.set_flag(StatusFlag::C, self.a >= self.fetched);
-        self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
-        self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
-        return 0;
+        self.compare(self.a, self.fetched);
+        return 1;
     }
 
     fn CPX(&mut self) -> u8{
-        self.fetch();
-        self.temp = self.x as u16 - self.fetched as u16;
-        self.set_flag(StatusFlag::C, self.x >= self.fetched);
-        self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
-        self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
-        return 0;
+        self.compare(self.x, self.fetched);
+        return 1;
     }
 
     fn CPY(&mut self) -> u8{
-        self.fetch();
-        self.temp = self.y as u16 - self.fetched as u16;
-        self.set_flag(StatusFlag::C, self.y >= self.fetched);
-        self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
-        self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
-        return 0;
+        self.compare(self.y, self.fetched);
+        return 1;
     }
 
     fn DEC(&mut self) -> u8{
-        self.fetch();
-        self.temp = self.fetched as u16 - 1;
-        self.write(self.addr_abs, (self.temp & 0x00FF).try_into().unwrap());
+        self.temp = (self.fetched as u16).wrapping_sub(1);
+        if self.implied(){
+            self.a = (self.temp & 0x00FF) as u8;
+        }
+        else{
+            self.write(self.addr_abs, (self.temp & 0x00FF).try_into().unwrap());
+        }
         self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
         self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn DEX(&mut self) -> u8{
-        self.x -= 1;
+        self.x = self.x.wrapping_sub(1);
         self.set_flag(StatusFlag::Z, self.x == 0);
         self.set_flag(StatusFlag::N, self.x & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn DEY(&mut self) -> u8{
-        self.y -= 1;
+        self.y = self.y.wrapping_sub(1);
         self.set_flag(StatusFlag::Z, self.y == 0);
         self.set_flag(StatusFlag::N, self.y & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn EOR(&mut self) -> u8{
-        self.fetch();
         self.a ^= self.fetched;
         self.set_flag(StatusFlag::Z, self.a == 0);
         self.set_flag(StatusFlag::N, self.a & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn INC(&mut self) -> u8{
-        self.fetch();
         self.temp = self.fetched as u16 + 1;
-        self.write(self.addr_abs, (self.temp & 0x00FF).try_into().unwrap());
+        if self.implied(){
+            self.a = (self.temp & 0x00FF) as u8;
+        }
+        else{
+            self.write(self.addr_abs, (self.temp & 0x00FF).try_into().unwrap());
+        }
         self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
         self.set_flag(StatusFlag::N, self.temp & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn INX(&mut self) -> u8{
-        self.x += 1;
+        self.x = self.x.wrapping_add(1);
         self.set_flag(StatusFlag::Z, self.x == 0);
         self.set_flag(StatusFlag::N, self.x & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn INY(&mut self) -> u8{
-        self.y += 1;
+        self.y = self.y.wrapping_add(1);
         self.set_flag(StatusFlag::Z, self.y == 0);
         self.set_flag(StatusFlag::N, self.y & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn JMP(&mut self) -> u8{
-        self.pc = self.addr_abs;
+        if self.address() == 1{
+            self.pc = self.addr_abs;
+            return 1;
+        }
         return 0;
     }
 
     fn JSR(&mut self) -> u8{
-        self.pc -= 1;
-        self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-        self.sp -= 1;
-        self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF).try_into().unwrap());
-        self.sp -= 1;
-
-        self.pc = self.addr_abs;
+        match self.step {
+            1 => {
+                self.addr_abs = self.read(self.pc) as u16;
+                self.pc = self.pc.wrapping_add(1);
+            },
+            // internal operation: dummy read of the stack
+            2 => { self.read(0x0100 + self.sp as u16); },
+            // pc points at the high byte of the target, which is what RTS expects
+            3 => self.push(((self.pc >> 8) & 0x00FF).try_into().unwrap()),
+            4 => self.push((self.pc & 0x00FF).try_into().unwrap()),
+            _ => {
+                self.addr_abs |= (self.read(self.pc) as u16) << 8;
+                self.pc = self.addr_abs;
+                return 1;
+            },
+        }
         return 0;
     }
 
     fn LDA(&mut self) -> u8{
-        self.fetch();
         self.a = self.fetched;
         self.set_flag(StatusFlag::Z, self.a == 0);
         self.set_flag(StatusFlag::N, self.a & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn LDX(&mut self) -> u8{
-        self.fetch();
         self.x = self.fetched;
         self.set_flag(StatusFlag::Z, self.x == 0);
         self.set_flag(StatusFlag::N, self.x & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn LDY(&mut self) -> u8{
-        self.fetch();
         self.y = self.fetched;
         self.set_flag(StatusFlag::Z, self.y == 0);
         self.set_flag(StatusFlag::N, self.y & 0x0080 > 0);
-        return 0;
+        return 1;
     }
 
     fn LSR(&mut self) -> u8{
-        self.fetch();
         self.temp = (self.fetched as u16) >> 1;
         self.set_flag(StatusFlag::C, self.fetched & 1 > 0);
         self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0x00);
         self.set_flag(StatusFlag::N, self.temp & 0x80 > 0);
         let ret = (self.temp & 0x00FF) as u8;
-        if self.lookup[self.opcode as usize].addr_mode as usize != CPU::IMP as usize{
+        if self.implied(){
             self.a = ret;
         }
         else{
             self.write(self.addr_abs, ret);
         }
-        return 0;
+        return 1;
     }
 
     fn NOP(&mut self) -> u8{
-        if self.opcode == 0xFC{
-            return 1;
-        }
-        return 0;
+        // the unofficial multi-byte NOPs still read their operand
+        return 1;
     }
 
     fn ORA(&mut self) -> u8{
-        self.fetch();
-        self.a = self.a | self.fetched;
+        self.a |= self.fetched;
         self.set_flag(StatusFlag::Z, self.a == 0x00);
         self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
         return 1;
     }
 
     fn PHA(&mut self) -> u8{
-        self.write(0x0100 + self.sp as u16, self.a);
-        self.sp -= 1;
-        return 0;
+        if self.step == 1{
+            self.IMP();
+            return 0;
+        }
+        self.push(self.a);
+        return 1;
     }
 
     fn PHP(&mut self) -> u8{
-        self.write(0x0100 + self.sp as u16, self.status | StatusFlag::B as u8 | StatusFlag::U as u8);
-        self.sp -= 1;
-        self.set_flag(StatusFlag::B, false);
-        self.set_flag(StatusFlag::U, false);
-
-        return 0;
+        if self.step == 1{
+            self.IMP();
+            return 0;
+        }
+        self.push(self.status | StatusFlag::B as u8 | StatusFlag::U as u8);
+        return 1;
     }
 
     fn PLA(&mut self) -> u8{
-        self.sp += 1;
-        self.a = self.read(0x0100 + self.sp as u16);
+        if self.pull_step(){
+            return 0;
+        }
+        self.a = self.pop();
         self.set_flag(StatusFlag::Z, self.a == 0);
         self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
-
-        return 0;
+        return 1;
     }
 
     fn PLP(&mut self) -> u8{
-        self.sp += 1;
-        self.status = self.read(0x0100 + self.sp as u16);
+        if self.pull_step(){
+            return 0;
+        }
+        self.status = self.pop();
+        self.set_flag(StatusFlag::B, false);
         self.set_flag(StatusFlag::U, true);
-
-        return 0;
+        return 1;
     }
 
     fn ROL(&mut self) -> u8{
-        self.fetch();
         self.temp = ((self.fetched as u16) << 1) | self.read_flag(StatusFlag::C) as u16;
         self.set_flag(StatusFlag::C, self.temp & 0xFF00 > 0);
         self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
         self.set_flag(StatusFlag::N, self.temp & 0x80 > 0);
         let ret = (self.temp & 0x00FF) as u8;
-        if self.lookup[self.opcode as usize].addr_mode as usize != CPU::IMP as usize{
+        if self.implied(){
             self.a = ret;
         }
         else{
             self.write(self.addr_abs, ret);
         }
-        return 0;
+        return 1;
     }
 
     fn ROR(&mut self) -> u8{
-        self.fetch();
         self.temp = ((self.fetched as u16) >> 1) | ((self.read_flag(StatusFlag::C) as u16) << 7);
         self.set_flag(StatusFlag::C, self.fetched & 1 > 0);
         self.set_flag(StatusFlag::Z, self.temp & 0x00FF == 0);
         self.set_flag(StatusFlag::N, self.temp & 0x80 > 0);
         let ret = (self.temp & 0x00FF) as u8;
-        if self.lookup[self.opcode as usize].addr_mode as usize != CPU::IMP as usize{
+        if self.implied(){
             self.a = ret;
         }
         else{
             self.write(self.addr_abs, ret);
         }
-        return 0;
+        return 1;
     }
 
     fn RTI(&mut self) -> u8{
-        self.sp += 1;
-        self.status = self.read(0x0100 + self.sp as u16);
-        self.status &= !(StatusFlag::B as u8);
-        self.status &= !(StatusFlag::U as u8);
-        
-        self.sp += 1;
-        self.pc = self.read(0x0100 + self.sp as u16) as u16;
-        self.sp += 1;
-        self.pc |= (self.read(0x0100 + self.sp as u16) as u16) << 8;
-
+        match self.step {
+            1 | 2 => { self.pull_step(); },
+            3 => {
+                self.status = self.pop();
+                self.status &= !(StatusFlag::B as u8);
+                self.status |= StatusFlag::U as u8;
+            },
+            4 => self.pc = self.pop() as u16,
+            _ => {
+                self.pc |= (self.pop() as u16) << 8;
+                return 1;
+            },
+        }
         return 0;
     }
 
     fn RTS(&mut self) -> u8{
-        self.sp += 1;
-        self.pc = self.read(0x0100 + self.sp as u16) as u16;
-        self.sp += 1;
-        self.pc |= (self.read(0x0100 + self.sp as u16) as u16) << 8;
-        
-        self.pc += 1;
+        match self.step {
+            1 | 2 => { self.pull_step(); },
+            3 => self.pc = self.pop() as u16,
+            4 => self.pc |= (self.pop() as u16) << 8,
+            _ => {
+                // dummy read of the pulled address before it is incremented
+                self.read(self.pc);
+                self.pc = self.pc.wrapping_add(1);
+                return 1;
+            },
+        }
         return 0;
     }
 
     fn SEC(&mut self) -> u8{
         self.set_flag(StatusFlag::C, true);
-        return 0;
+        return 1;
     }
 
     fn SED(&mut self) -> u8{
         self.set_flag(StatusFlag::D, true);
-        return 0;
+        return 1;
     }
 
     fn SEI(&mut self) -> u8{
         self.set_flag(StatusFlag::I, true);
-        return 0;
+        return 1;
     }
 
     fn STA(&mut self) -> u8{
         self.write(self.addr_abs, self.a);
-        return 0;
+        return 1;
     }
 
     fn STX(&mut self) -> u8{
         self.write(self.addr_abs, self.x);
-        return 0;
+        return 1;
     }
 
     fn STY(&mut self) -> u8{
         self.write(self.addr_abs, self.y);
-        return 0;
+        return 1;
     }
 
     fn TAX(&mut self) -> u8{
         self.x = self.a;
         self.set_flag(StatusFlag::Z, self.x == 0);
         self.set_flag(StatusFlag::N, self.x & 0x80 > 0);
-        return 0;
+        return 1;
     }
 
     fn TAY(&mut self) -> u8{
         self.y = self.a;
         self.set_flag(StatusFlag::Z, self.y == 0);
         self.set_flag(StatusFlag::N, self.y & 0x80 > 0);
-        return 0;
+        return 1;
     }
 
     fn TSX(&mut self) -> u8{
         self.x = self.sp;
         self.set_flag(StatusFlag::Z, self.x == 0);
         self.set_flag(StatusFlag::N, self.x & 0x80 > 0);
-        return 0;
+        return 1;
     }
 
     fn TXA(&mut self) -> u8{
         self.a = self.x;
         self.set_flag(StatusFlag::Z, self.a == 0);
         self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
-        return 0;
+        return 1;
     }
 
     fn TXS(&mut self) -> u8{
         self.sp = self.x;
-        return 0;
+        return 1;
     }
 
     fn TYA(&mut self) -> u8{
         self.a = self.y;
         self.set_flag(StatusFlag::Z, self.a == 0);
         self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    // unofficial operations
+    // https://www.nesdev.org/wiki/Programming_with_unofficial_opcodes
+
+    fn ALR(&mut self) -> u8{
+        self.a &= self.fetched;
+        self.set_flag(StatusFlag::C, self.a & 1 > 0);
+        self.a >>= 1;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, false);
+        return 1;
+    }
+
+    fn ANC(&mut self) -> u8{
+        self.a &= self.fetched;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        self.set_flag(StatusFlag::C, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn ARR(&mut self) -> u8{
+        self.a = ((self.a & self.fetched) >> 1) | ((self.read_flag(StatusFlag::C) as u8) << 7);
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        self.set_flag(StatusFlag::C, self.a & 0x40 > 0);
+        self.set_flag(StatusFlag::V, ((self.a >> 6) ^ (self.a >> 5)) & 1 > 0);
+        return 1;
+    }
+
+    fn AXS(&mut self) -> u8{
+        let ax = self.a & self.x;
+        self.compare(ax, self.fetched);
+        self.x = ax.wrapping_sub(self.fetched);
+        return 1;
+    }
+
+    fn DCP(&mut self) -> u8{
+        let value = self.fetched.wrapping_sub(1);
+        self.write(self.addr_abs, value);
+        self.compare(self.a, value);
+        return 1;
+    }
+
+    fn ISC(&mut self) -> u8{
+        let value = self.fetched.wrapping_add(1);
+        self.write(self.addr_abs, value);
+        self.subtract_with_carry(value);
+        return 1;
+    }
+
+    fn JAM(&mut self) -> u8{
+        // the real CPU locks up with $FFFF on the bus until it is reset
+        self.read(0xFFFF);
+        self.step = 1;
         return 0;
     }
 
-    fn UNK(&mut self) -> u8{
+    fn LAS(&mut self) -> u8{
+        self.a = self.fetched & self.sp;
+        self.x = self.a;
+        self.sp = self.a;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn LAX(&mut self) -> u8{
+        self.a = self.fetched;
+        self.x = self.fetched;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn LXA(&mut self) -> u8{
+        // unstable: the 2A03 behaves as if the "magic" constant were 0xFF
+        self.a = (self.a | 0xFF) & self.fetched;
+        self.x = self.a;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn RLA(&mut self) -> u8{
+        let value = (self.fetched << 1) | self.read_flag(StatusFlag::C) as u8;
+        self.set_flag(StatusFlag::C, self.fetched & 0x80 > 0);
+        self.write(self.addr_abs, value);
+        self.a &= value;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn RRA(&mut self) -> u8{
+        let value = (self.fetched >> 1) | ((self.read_flag(StatusFlag::C) as u8) << 7);
+        self.set_flag(StatusFlag::C, self.fetched & 1 > 0);
+        self.write(self.addr_abs, value);
+        self.add_with_carry(value);
+        return 1;
+    }
+
+    fn SAX(&mut self) -> u8{
+        self.write(self.addr_abs, self.a & self.x);
+        return 1;
+    }
+
+    fn SLO(&mut self) -> u8{
+        let value = self.fetched << 1;
+        self.set_flag(StatusFlag::C, self.fetched & 0x80 > 0);
+        self.write(self.addr_abs, value);
+        self.a |= value;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn SRE(&mut self) -> u8{
+        let value = self.fetched >> 1;
+        self.set_flag(StatusFlag::C, self.fetched & 1 > 0);
+        self.write(self.addr_abs, value);
+        self.a ^= value;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    fn XAA(&mut self) -> u8{
+        // unstable: the "magic" constant varies between chips, 0xEE is the common value
+        self.a = (self.a | 0xEE) & self.x & self.fetched;
+        self.set_flag(StatusFlag::Z, self.a == 0);
+        self.set_flag(StatusFlag::N, self.a & 0x80 > 0);
+        return 1;
+    }
+
+    // AHX, SHX, SHY and TAS store `value & (H + 1)`, where H is the high byte of the
+    // unindexed base address. When indexing crosses a page, the stored value also
+    // replaces the high byte of the target address.
+    fn unstable_store(&mut self, value: u8, index: u8){
+        let base = self.addr_abs.wrapping_sub(index as u16);
+        let data = value & ((base >> 8) as u8).wrapping_add(1);
+        if base & 0xFF00 != self.addr_abs & 0xFF00{
+            self.addr_abs = ((data as u16) << 8) | (self.addr_abs & 0x00FF);
+        }
+        self.write(self.addr_abs, data);
+    }
+
+    fn AHX(&mut self) -> u8{
+        self.unstable_store(self.a & self.x, self.y);
+        return 1;
+    }
+
+    fn SHX(&mut self) -> u8{
+        self.unstable_store(self.x, self.y);
+        return 1;
+    }
+
+    fn SHY(&mut self) -> u8{
+        self.unstable_store(self.y, self.x);
+        return 1;
+    }
+
+    fn TAS(&mut self) -> u8{
+        self.sp = self.a & self.x;
+        self.unstable_store(self.sp, self.y);
+        return 1;
+    }
+
+    // 65C02 operations
+
+    fn BRA(&mut self) -> u8{
+        return self.branch(true);
+    }
+
+    // BBRn and BBSn test bit n of a zero page byte, taken from the opcode
+    fn BBR(&mut self) -> u8{
+        if self.step <= 3{
+            self.address();
+            return 0;
+        }
+        let bit = (self.opcode >> 4) & 7;
+        return self.branch_from(4, self.fetched & (1 << bit) == 0);
+    }
+
+    fn BBS(&mut self) -> u8{
+        if self.step <= 3{
+            self.address();
+            return 0;
+        }
+        let bit = (self.opcode >> 4) & 7;
+        return self.branch_from(4, self.fetched & (1 << bit) > 0);
+    }
+
+    fn PHX(&mut self) -> u8{
+        if self.step == 1{
+            self.IMP();
+            return 0;
+        }
+        self.push(self.x);
+        return 1;
+    }
+
+    fn PHY(&mut self) -> u8{
+        if self.step == 1{
+            self.IMP();
+            return 0;
+        }
+        self.push(self.y);
+        return 1;
+    }
+
+    fn PLX(&mut self) -> u8{
+        if self.pull_step(){
+            return 0;
+        }
+        self.x = self.pop();
+        self.set_flag(StatusFlag::Z, self.x == 0);
+        self.set_flag(StatusFlag::N, self.x & 0x80 > 0);
+        return 1;
+    }
+
+    fn PLY(&mut self) -> u8{
+        if self.pull_step(){
+            return 0;
+        }
+        self.y = self.pop();
+        self.set_flag(StatusFlag::Z, self.y == 0);
+        self.set_flag(StatusFlag::N, self.y & 0x80 > 0);
+        return 1;
+    }
+
+    fn RMB(&mut self) -> u8{
+        let bit = (self.opcode >> 4) & 7;
+        self.write(self.addr_abs, self.fetched & !(1 << bit));
+        return 1;
+    }
+
+    fn SMB(&mut self) -> u8{
+        let bit = (self.opcode >> 4) & 7;
+        self.write(self.addr_abs, self.fetched | (1 << bit));
+        return 1;
+    }
+
+    fn STP(&mut self) -> u8{
+        // the clock is stopped until the next reset
+        self.step = 1;
         return 0;
     }
 
-    // public methods
-    pub fn irq(&mut self){
-        if self.read_flag(StatusFlag::I){
-            self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-            self.sp -= 1;
-            self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF).try_into().unwrap());
-            self.sp -= 1;
+    fn STZ(&mut self) -> u8{
+        self.write(self.addr_abs, 0x00);
+        return 1;
+    }
 
-            self.set_flag(StatusFlag::B, false);
-            self.set_flag(StatusFlag::U, true);
-            self.set_flag(StatusFlag::I, true);
-            self.write(0x0100 + self.sp as u16, self.status);
-            self.sp -= 1;
+    fn TRB(&mut self) -> u8{
+        self.set_flag(StatusFlag::Z, self.a & self.fetched == 0);
+        self.write(self.addr_abs, self.fetched & !self.a);
+        return 1;
+    }
 
-            self.addr_abs = 0xFFFE;
-            let lo = self.read(self.addr_abs) as u16;
-            let hi = self.read(self.addr_abs+1) as u16;
-            self.pc = (hi << 8) | lo;
+    fn TSB(&mut self) -> u8{
+        self.set_flag(StatusFlag::Z, self.a & self.fetched == 0);
+        self.write(self.addr_abs, self.fetched | self.a);
+        return 1;
+    }
 
-            self.cycles = 7;
+    fn WAI(&mut self) -> u8{
+        if self.step == 1{
+            self.IMP();
+            return 0;
         }
+        // sleeps until an interrupt is requested, even one masked by I
+        if self.irq_line() || self.nmi_pending{
+            return 1;
+        }
+        self.step = 1;
+        return 0;
     }
 
-    pub fn nmi(&mut self){
-        if self.read_flag(StatusFlag::I){
-            self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF).try_into().unwrap());
-            self.sp -= 1;
-            self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF).try_into().unwrap());
-            self.sp -= 1;
+    // public methods
+    // interrupts
+
+    pub fn set_irq_line(&mut self, source: IrqSource, asserted: bool){
+        if asserted{
+            self.irq_lines |= source as u8;
+        }
+        else{
+            self.irq_lines &= !(source as u8);
+        }
+    }
 
-            self.set_flag(StatusFlag::B, false);
-            self.set_flag(StatusFlag::U, true);
-            self.set_flag(StatusFlag::I, true);
-            self.write(0x0100 + self.sp as u16, self.status);
-            self.sp -= 1;
+    // held by a source set here or by a device on the bus
+    pub fn irq_line(&self) -> bool{
+        return self.irq_lines | self.bus.irq() != 0;
+    }
 
-            self.addr_abs = 0xFFFA;
-            let lo = self.read(self.addr_abs) as u16;
-            let hi = self.read(self.addr_abs+1) as u16;
-            self.pc = (hi << 8) | lo;
+    // NMI is edge triggered: only a transition to asserted requests an interrupt
+    pub fn set_nmi_line(&mut self, asserted: bool){
+        self.nmi_line = asserted;
+    }
 
-            self.cycles = 7;
+    // Sampled at the end of every cycle. An interrupt is taken after an instruction
+    // if it was seen at the end of the instruction's penultimate cycle, which is what
+    // the prev_ values hold once the final cycle has been polled.
+    fn poll_interrupts(&mut self){
+        let nmi_line = self.nmi_line || self.bus.nmi();
+        self.prev_nmi_pending = self.nmi_pending;
+        if nmi_line && !self.prev_nmi_line{
+            self.nmi_pending = true;
         }
+        self.prev_nmi_line = nmi_line;
+
+        self.prev_irq_poll = self.irq_poll;
+        self.irq_poll = self.irq_line() && !self.read_flag(StatusFlag::I);
     }
 
+    // Advances the CPU by exactly one cycle, performing that cycle's bus access. When an
+    // execute breakpoint fires the cycle is not run, leaving the CPU in front of the
+    // instruction; the next call runs it.
     pub fn clock(&mut self){
-        if self.cycles == 0{
+        if self.step == 0 && self.pending_interrupt.is_none() && self.execute_breakpoint(){
+            return;
+        }
+        let done = if self.step == 0 {
+            self.fetch_opcode();
+            // the 65C02's single cycle NOPs
+            self.lookup[self.opcode as usize].cycles == 1
+        } else {
+            self.execute_cycle()
+        };
+        self.poll_interrupts();
+        self.bus.tick();
+
+        if done{
+            self.step = 0;
+            // BRK and interrupt sequences always let the first instruction of the handler run
+            if self.opcode != 0x00{
+                if self.prev_nmi_pending{
+                    self.nmi_pending = false;
+                    self.pending_interrupt = Some(0xFFFA);
+                }
+                else if self.prev_irq_poll{
+                    self.pending_interrupt = Some(0xFFFE);
+                }
+            }
+        }
+        else{
+            self.step += 1;
+        }
+        self.clock_count += 1;
+    }
+
+    fn execute_breakpoint(&mut self) -> bool{
+        if std::mem::take(&mut self.break_skip) || self.breakpoints.is_empty(){
+            return false;
+        }
+        match self.breakpoints.check_execute(self.pc) {
+            Some(found) => {
+                self.instr_pc = self.pc;
+                self.hit_breakpoint(found, self.pc, None);
+                self.break_skip = true;
+                return true;
+            },
+            None => return false,
+        }
+    }
+
+    fn fetch_opcode(&mut self){
+        self.set_flag(StatusFlag::U, true);
+        self.instr_pc = self.pc;
+        self.interrupt = self.pending_interrupt.take();
+        if self.interrupt.is_some(){
+            // the opcode fetch still happens but is discarded in favour of BRK
+            self.read(self.pc);
+            self.opcode = 0x00;
+        }
+        else{
+            self.trace();
             self.opcode = self.read(self.pc);
+            self.pc = self.pc.wrapping_add(1);
+        }
 
-            self.set_flag(StatusFlag::U, true);
-            self.pc+=1;
-            let instr = self.lookup[self.opcode as usize].clone();
-            self.cycles = instr.cycles;
-            let extra_cycle1 = (instr.addr_mode)(self);
-            let extra_cycle2 = (instr.operation)(self);
-            self.cycles += extra_cycle1 & extra_cycle2;
-            self.set_flag(StatusFlag::U, true);
+        if !self.breakpoints.is_empty(){
+            let kind = match (self.interrupt, self.opcode) {
+                (Some(0xFFFA), _) => Some(InterruptKind::Nmi),
+                (Some(0xFFFE), _) => Some(InterruptKind::Irq),
+                (None, 0x00) => Some(InterruptKind::Brk),
+                _ => None,
+            };
+            if let Some(found) = kind.and_then(|k| self.breakpoints.check_interrupt(k)){
+                self.hit_breakpoint(found, self.interrupt.unwrap_or(0xFFFE), None);
+            }
+        }
+
+        self.kind = self.kinds[self.opcode as usize];
+        self.addr_step = None;
+        let immediate = std::ptr::fn_addr_eq(self.lookup[self.opcode as usize].addr_mode, CPU::IMM as AddrMode<M>);
+        if immediate && self.kind == OpKind::Read{
+            self.IMM();
+            self.addr_step = Some(0);
+        }
+    }
+
+    // runs cycle `self.step` of the current instruction, returns true once it has completed
+    fn execute_cycle(&mut self) -> bool{
+        let operation = self.lookup[self.opcode as usize].operation;
+        match self.kind {
+            OpKind::Control => return operation(self) == 1,
+            OpKind::Implied => {
+                self.IMP();
+                return operation(self) == 1;
+            },
+            _ => {},
+        }
+
+        let addr_step = match self.addr_step {
+            Some(addr_step) => addr_step,
+            None => {
+                if self.address() == 1{
+                    self.addr_step = Some(self.step);
+                }
+                return false;
+            },
+        };
+
+        match (self.kind, self.step - addr_step) {
+            (OpKind::Read, 1) => {
+                self.fetched = self.read(self.addr_abs);
+                return operation(self) == 1;
+            },
+            (OpKind::Read, _) => {
+                // decimal mode correction on the 65C02
+                self.read(self.addr_abs);
+                return true;
+            },
+            (OpKind::ReadModifyWrite, 1) => {
+                self.fetched = self.read(self.addr_abs);
+                return false;
+            },
+            (OpKind::ReadModifyWrite, 2) => {
+                // the unmodified value is written back while the new one is computed,
+                // the 65C02 reads it again instead
+                if self.variant == Variant::Cmos65C02{
+                    self.read(self.addr_abs);
+                }
+                else{
+                    self.write(self.addr_abs, self.fetched);
+                }
+                return false;
+            },
+            _ => return operation(self) == 1,
         }
     }
 
+    // true at an instruction boundary
     pub fn complete(&self) -> bool{
-        return self.cycles == 0;
+        return self.step == 0;
+    }
+
+    // breakpoints
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize{
+        return self.breakpoints.add(breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool{
+        return self.breakpoints.remove(id);
+    }
+
+    pub fn clear_breakpoints(&mut self){
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints{
+        return &self.breakpoints;
+    }
+
+    // the breakpoint that fired since the last call, for callers driving clock() directly
+    pub fn take_breakpoint_hit(&mut self) -> Option<BreakpointHit>{
+        return self.breakpoint_hit.take();
+    }
+
+    // true once JAM or STP has locked up the CPU
+    pub fn halted(&self) -> bool{
+        return self.step > 0 && matches!(self.lookup[self.opcode as usize].name, "JAM" | "STP");
+    }
+
+    // true while a WAI sleeps with no interrupt requested to wake it
+    pub fn waiting(&self) -> bool{
+        return self.step > 1 && self.lookup[self.opcode as usize].name == "WAI" && !(self.irq_line() || self.nmi_pending);
+    }
+
+    // save states
+    // Everything needed to carry on mid-instruction is saved. Breakpoints and the
+    // tracer belong to the debugger and are left as they are.
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter){
+        state.u8(self.variant as u8);
+        for value in [self.a, self.x, self.y, self.sp, self.status, self.fetched, self.opcode, self.step, self.irq_lines]{
+            state.u8(value);
+        }
+        for value in [self.pc, self.temp, self.addr_abs, self.addr_rel, self.vector, self.instr_pc]{
+            state.u16(value);
+        }
+        state.u64(self.clock_count);
+        state.option_u8(self.addr_step);
+        state.option_u16(self.pending_interrupt);
+        state.option_u16(self.interrupt);
+        for value in [self.nmi_line, self.prev_nmi_line, self.nmi_pending, self.prev_nmi_pending, self.irq_poll, self.prev_irq_poll]{
+            state.bool(value);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError>{
+        if state.u8()? != self.variant as u8{
+            return Err(SaveStateError::Mismatch);
+        }
+        for value in [&mut self.a, &mut self.x, &mut self.y, &mut self.sp, &mut self.status, &mut self.fetched,
+                &mut self.opcode, &mut self.step, &mut self.irq_lines]{
+            *value = state.u8()?;
+        }
+        for value in [&mut self.pc, &mut self.temp, &mut self.addr_abs, &mut self.addr_rel, &mut self.vector, &mut self.instr_pc]{
+            *value = state.u16()?;
+        }
+        self.clock_count = state.u64()?;
+        self.addr_step = state.option_u8()?;
+        // no instruction or interrupt sequence lasts longer than 8 cycles, and the
+        // address is worked out during the one in progress
+        if self.step > 7 || self.addr_step.is_some_and(|addr_step| addr_step > self.step){
+            return Err(SaveStateError::Invalid);
+        }
+        self.pending_interrupt = state.option_u16()?;
+        self.interrupt = state.option_u16()?;
+        for value in [&mut self.nmi_line, &mut self.prev_nmi_line, &mut self.nmi_pending, &mut self.prev_nmi_pending,
+                &mut self.irq_poll, &mut self.prev_irq_poll]{
+            *value = state.bool()?;
+        }
+        self.kind = self.kinds[self.opcode as usize];
+        self.breakpoint_hit = None;
+        self.break_skip = false;
+        return Ok(());
+    }
+
+    // execution control
+    // Everything apart from step_cycles stops at an instruction boundary. An interrupt
+    // sequence counts as an instruction of its own.
+
+    // Runs the rest of the current instruction, or the whole of the next one. A WAI
+    // with nothing to wake it stops the step after a cycle of sleep.
+    pub fn step_instruction(&mut self) -> StopReason{
+        self.resume();
+        return self.next_instruction(None);
+    }
+
+    // stops straight after the cycle on which a breakpoint fires
+    pub fn step_cycles(&mut self, cycles: u64) -> StopReason{
+        self.resume();
+        for _ in 0..cycles{
+            self.clock();
+            if let Some(hit) = self.breakpoint_hit.take(){
+                return StopReason::Breakpoint(hit);
+            }
+            if self.halted(){
+                return StopReason::Halted;
+            }
+        }
+        return StopReason::Done;
+    }
+
+    // runs to the first instruction boundary on the next scanline
+    pub fn step_scanline(&mut self) -> StopReason{
+        let (scanline, _) = self.bus.ppu_position();
+        return self.run_instructions(FRAME_STEP_LIMIT, |cpu| cpu.bus.ppu_position().0 != scanline);
+    }
+
+    // runs to the first instruction boundary after the pre-render line begins
+    pub fn step_frame(&mut self) -> StopReason{
+        let mut prev = self.bus.ppu_position().0;
+        return self.run_instructions(FRAME_STEP_LIMIT, |cpu| {
+            let scanline = cpu.bus.ppu_position().0;
+            let wrapped = scanline < prev;
+            prev = scanline;
+            wrapped
+        });
+    }
+
+    // runs at least one instruction, stopping once pc reaches `pc`
+    pub fn run_until_pc(&mut self, pc: u16, max_cycles: u64) -> StopReason{
+        return self.run_instructions(max_cycles, |cpu| cpu.pc == pc);
+    }
+
+    // runs at least one instruction, stopping at the first boundary where `predicate` holds
+    pub fn run_until<F: FnMut(&CPU<M>) -> bool>(&mut self, max_cycles: u64, predicate: F) -> StopReason{
+        return self.run_instructions(max_cycles, predicate);
+    }
+
+    // Steps one instruction, but runs a JSR or BRK through to the instruction after
+    // it. The stack pointer must match too, so recursive calls to the same routine
+    // are stepped over as a whole.
+    pub fn step_over(&mut self, max_cycles: u64) -> StopReason{
+        if !self.complete(){
+            return self.step_instruction();
+        }
+        let length = match self.peek(self.pc) {
+            0x20 => 3,
+            0x00 => 2,
+            _ => return self.step_instruction(),
+        };
+        let ret = self.pc.wrapping_add(length);
+        let sp = self.sp;
+        return self.run_instructions(max_cycles, |cpu| cpu.pc == ret && cpu.sp == sp);
+    }
+
+    // Runs until an RTS or RTI returns from the current routine or interrupt handler,
+    // which leaves the stack above where it started. The stack wraps around its page,
+    // so above means within half a page.
+    pub fn step_out(&mut self, max_cycles: u64) -> StopReason{
+        let sp = self.sp;
+        return self.run_instructions(max_cycles, |cpu| {
+            matches!(cpu.opcode, 0x40 | 0x60) && (cpu.sp.wrapping_sub(sp) as i8) > 0
+        });
+    }
+
+    // Execute breakpoints stop the CPU in front of the instruction. Anything else
+    // that fires lets the current instruction, or interrupt sequence, finish first.
+    // The cycle limit is checked on every cycle, since a WAI may never finish.
+    fn next_instruction(&mut self, limit: Option<u64>) -> StopReason{
+        loop{
+            self.clock();
+            if self.complete() || self.halted(){
+                if let Some(hit) = self.breakpoint_hit.take(){
+                    return StopReason::Breakpoint(hit);
+                }
+                if self.halted(){
+                    return StopReason::Halted;
+                }
+                return StopReason::Done;
+            }
+            match limit {
+                Some(limit) if self.clock_count >= limit => return StopReason::CycleLimit,
+                None if self.waiting() => return StopReason::Waiting,
+                _ => {},
+            }
+        }
+    }
+
+    // called by every control method, which always makes progress from a breakpoint
+    fn resume(&mut self){
+        self.breakpoint_hit = None;
+        if self.complete(){
+            self.break_skip = true;
+        }
+    }
+
+    fn run_instructions<F: FnMut(&CPU<M>) -> bool>(&mut self, max_cycles: u64, mut stop: F) -> StopReason{
+        let limit = self.clock_count.saturating_add(max_cycles);
+        self.resume();
+        loop{
+            let reason = self.next_instruction(Some(limit));
+            if reason != StopReason::Done{
+                return reason;
+            }
+            if stop(self){
+                return StopReason::Done;
+            }
+            if self.clock_count >= limit{
+                return StopReason::CycleLimit;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+    use crate::cartridge::tests::ines;
+    use crate::memory::FlatMemory;
+    use crate::nes::Nes;
+
+    const C: u8 = StatusFlag::C as u8;
+    const Z: u8 = StatusFlag::Z as u8;
+    const I: u8 = StatusFlag::I as u8;
+    const D: u8 = StatusFlag::D as u8;
+    const B: u8 = StatusFlag::B as u8;
+    const U: u8 = StatusFlag::U as u8;
+    const V: u8 = StatusFlag::V as u8;
+    const N: u8 = StatusFlag::N as u8;
+
+    // a 2A03 about to run `program` from $0200, with an empty stack at $01FD
+    fn cpu_with(program: &[u8]) -> CPU<FlatMemory> {
+        let mut memory = FlatMemory::new();
+        memory.load(0x0200, program);
+        let mut cpu = CPU::new(memory);
+        cpu.pc = 0x0200;
+        cpu.sp = 0xFD;
+        cpu.status = U;
+        return cpu;
+    }
+
+    // runs one instruction, returning the cycles it took
+    fn step(cpu: &mut CPU<FlatMemory>) -> u64 {
+        let start = cpu.cycles();
+        assert_eq!(cpu.step_instruction(), StopReason::Done);
+        return cpu.cycles() - start;
+    }
+
+    // Cycles for every official opcode with no page crossed and no branch taken
+    // http://www.6502.org/tutorials/6502opcodes.html
+    const OFFICIAL_CYCLES: [(u8, u64); 151] = [
+        (0x00, 7), (0x01, 6), (0x05, 3), (0x06, 5), (0x08, 3), (0x09, 2), (0x0A, 2), (0x0D, 4), (0x0E, 6),
+        (0x10, 2), (0x11, 5), (0x15, 4), (0x16, 6), (0x18, 2), (0x19, 4), (0x1D, 4), (0x1E, 7),
+        (0x20, 6), (0x21, 6), (0x24, 3), (0x25, 3), (0x26, 5), (0x28, 4), (0x29, 2), (0x2A, 2), (0x2C, 4), (0x2D, 4), (0x2E, 6),
+        (0x30, 2), (0x31, 5), (0x35, 4), (0x36, 6), (0x38, 2), (0x39, 4), (0x3D, 4), (0x3E, 7),
+        (0x40, 6), (0x41, 6), (0x45, 3), (0x46, 5), (0x48, 3), (0x49, 2), (0x4A, 2), (0x4C, 3), (0x4D, 4), (0x4E, 6),
+        (0x50, 2), (0x51, 5), (0x55, 4), (0x56, 6), (0x58, 2), (0x59, 4), (0x5D, 4), (0x5E, 7),
+        (0x60, 6), (0x61, 6), (0x65, 3), (0x66, 5), (0x68, 4), (0x69, 2), (0x6A, 2), (0x6C, 5), (0x6D, 4), (0x6E, 6),
+        (0x70, 2), (0x71, 5), (0x75, 4), (0x76, 6), (0x78, 2), (0x79, 4), (0x7D, 4), (0x7E, 7),
+        (0x81, 6), (0x84, 3), (0x85, 3), (0x86, 3), (0x88, 2), (0x8A, 2), (0x8C, 4), (0x8D, 4), (0x8E, 4),
+        (0x90, 2), (0x91, 6), (0x94, 4), (0x95, 4), (0x96, 4), (0x98, 2), (0x99, 5), (0x9A, 2), (0x9D, 5),
+        (0xA0, 2), (0xA1, 6), (0xA2, 2), (0xA4, 3), (0xA5, 3), (0xA6, 3), (0xA8, 2), (0xA9, 2), (0xAA, 2), (0xAC, 4), (0xAD, 4), (0xAE, 4),
+        (0xB0, 2), (0xB1, 5), (0xB4, 4), (0xB5, 4), (0xB6, 4), (0xB8, 2), (0xB9, 4), (0xBA, 2), (0xBC, 4), (0xBD, 4), (0xBE, 4),
+        (0xC0, 2), (0xC1, 6), (0xC4, 3), (0xC5, 3), (0xC6, 5), (0xC8, 2), (0xC9, 2), (0xCA, 2), (0xCC, 4), (0xCD, 4), (0xCE, 6),
+        (0xD0, 2), (0xD1, 5), (0xD5, 4), (0xD6, 6), (0xD8, 2), (0xD9, 4), (0xDD, 4), (0xDE, 7),
+        (0xE0, 2), (0xE1, 6), (0xE4, 3), (0xE5, 3), (0xE6, 5), (0xE8, 2), (0xE9, 2), (0xEA, 2), (0xEC, 4), (0xED, 4), (0xEE, 6),
+        (0xF0, 2), (0xF1, 5), (0xF5, 4), (0xF6, 6), (0xF8, 2), (0xF9, 4), (0xFD, 4), (0xFE, 7),
+    ];
+
+    #[test]
+    fn official_opcode_cycles() {
+        for &(opcode, cycles) in OFFICIAL_CYCLES.iter() {
+            let mut cpu = cpu_with(&[opcode, 0x00, 0x00]);
+            // branches on a clear flag are made to fall through by setting them all
+            if opcode & 0x1F == 0x10 && opcode & 0x20 == 0 {
+                cpu.status |= N | V | Z | C;
+            }
+            assert_eq!(step(&mut cpu), cycles, "opcode ${:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn official_opcode_names() {
+        let cpu = cpu_with(&[]);
+        for &(opcode, _) in OFFICIAL_CYCLES.iter() {
+            assert!(!cpu.is_unofficial(opcode, cpu.lookup[opcode as usize].name), "opcode ${:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn page_crossing_penalties() {
+        // LDA $02FF,X reads $0300 and takes an extra cycle
+        let mut cpu = cpu_with(&[0xBD, 0xFF, 0x02]);
+        cpu.x = 1;
+        cpu.bus.ram[0x0300] = 0x42;
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!(cpu.a, 0x42);
+
+        // LDA ($10),Y
+        let mut cpu = cpu_with(&[0xB1, 0x10]);
+        cpu.y = 0x01;
+        cpu.bus.load(0x0010, &[0xFF, 0x03]);
+        cpu.bus.ram[0x0400] = 0x24;
+        assert_eq!(step(&mut cpu), 6);
+        assert_eq!(cpu.a, 0x24);
+
+        // STA $02FF,X always takes 5
+        let mut cpu = cpu_with(&[0x9D, 0xFF, 0x02]);
+        cpu.x = 1;
+        cpu.a = 0x99;
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!(cpu.bus.ram[0x0300], 0x99);
+    }
+
+    #[test]
+    fn branches() {
+        // BEQ +4 not taken, taken, and taken onto the next page
+        let mut cpu = cpu_with(&[0xF0, 0x04]);
+        assert_eq!(step(&mut cpu), 2);
+        assert_eq!(cpu.pc, 0x0202);
+
+        let mut cpu = cpu_with(&[0xF0, 0x04]);
+        cpu.status |= Z;
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.pc, 0x0206);
+
+        let mut cpu = cpu_with(&[0xF0, 0xFC]);
+        cpu.status |= Z;
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!(cpu.pc, 0x01FE);
+    }
+
+    #[test]
+    fn loads_and_transfers_set_zero_and_negative() {
+        for (opcode, value, flags) in [(0xA9, 0x00, Z), (0xA9, 0x80, N), (0xA9, 0x7F, 0), (0xA2, 0x00, Z), (0xA0, 0xFF, N)] {
+            let mut cpu = cpu_with(&[opcode, value]);
+            step(&mut cpu);
+            assert_eq!(cpu.status, U | flags, "opcode ${:02X} #${:02X}", opcode, value);
+        }
+
+        // TAX, TAY, TXA, TYA and TSX set flags, TXS does not
+        let mut cpu = cpu_with(&[0xAA, 0xA8, 0x9A, 0xBA]);
+        cpu.a = 0x80;
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.x, cpu.y, cpu.status), (0x80, 0x80, U | N));
+        cpu.status = U;
+        step(&mut cpu);
+        assert_eq!((cpu.sp, cpu.status), (0x80, U));
+        cpu.x = 0;
+        step(&mut cpu);
+        assert_eq!((cpu.x, cpu.status), (0x80, U | N));
+    }
+
+    #[test]
+    fn adc_and_sbc() {
+        // (A, operand, carry in) => (A, flags)
+        let adc = [
+            (0x01, 0x01, false, 0x02, 0),
+            (0x7F, 0x01, false, 0x80, N | V),
+            (0xFF, 0x01, false, 0x00, Z | C),
+            (0x80, 0x80, false, 0x00, Z | C | V),
+            (0x10, 0x10, true, 0x21, 0),
+        ];
+        for (a, operand, carry, result, flags) in adc {
+            let mut cpu = cpu_with(&[0x69, operand]);
+            cpu.a = a;
+            cpu.status |= if carry { C } else { 0 };
+            step(&mut cpu);
+            assert_eq!((cpu.a, cpu.status), (result, U | flags), "ADC ${:02X} + ${:02X}", a, operand);
+        }
+
+        let sbc = [
+            (0x05, 0x03, true, 0x02, C),
+            (0x05, 0x03, false, 0x01, C),
+            (0x03, 0x05, true, 0xFE, N),
+            (0x80, 0x01, true, 0x7F, C | V),
+            (0x05, 0x05, true, 0x00, Z | C),
+        ];
+        for (a, operand, carry, result, flags) in sbc {
+            let mut cpu = cpu_with(&[0xE9, operand]);
+            cpu.a = a;
+            cpu.status |= if carry { C } else { 0 };
+            step(&mut cpu);
+            assert_eq!((cpu.a, cpu.status), (result, U | flags), "SBC ${:02X} - ${:02X}", a, operand);
+        }
+    }
+
+    #[test]
+    fn decimal_flag_is_ignored_by_the_2a03() {
+        let mut cpu = cpu_with(&[0x69, 0x01]);
+        cpu.a = 0x09;
+        cpu.status |= D;
+        step(&mut cpu);
+        assert_eq!(cpu.a, 0x0A);
+    }
+
+    // as cpu_with, for another variant
+    fn variant_with(variant: Variant, program: &[u8]) -> CPU<FlatMemory> {
+        let mut cpu = CPU::with_variant(cpu_with(program).bus, variant);
+        cpu.pc = 0x0200;
+        cpu.sp = 0xFD;
+        cpu.status = U;
+        return cpu;
+    }
+
+    #[test]
+    fn decimal_mode() {
+        // ADC #$01 to $99, then SBC #$01 from $10 with carry set
+        let (adc, sbc) = ([0x69, 0x01], [0xE9, 0x01]);
+        let run = |variant: Variant, program: &[u8], a: u8, status: u8| {
+            let mut cpu = variant_with(variant, program);
+            cpu.a = a;
+            cpu.status |= D | status;
+            let cycles = step(&mut cpu);
+            return (cpu.a, cpu.status & (C | Z), cycles);
+        };
+
+        // the NMOS 6502 takes Z from the binary sum
+        assert_eq!(run(Variant::Nmos6502, &adc, 0x99, 0), (0x00, C, 2));
+        assert_eq!(run(Variant::Nmos6502, &sbc, 0x10, C), (0x09, C, 2));
+        assert_eq!(run(Variant::Ricoh2A03, &adc, 0x99, 0), (0x9A, 0, 2));
+        assert_eq!(run(Variant::Ricoh2A03, &sbc, 0x10, C), (0x0F, C, 2));
+        // the 65C02 fixes Z and takes a cycle longer to do so
+        assert_eq!(run(Variant::Cmos65C02, &adc, 0x99, 0), (0x00, C | Z, 3));
+    }
+
+    #[test]
+    fn cmos_65c02_opcodes() {
+        // BRA +4, taken onto the next page too
+        let mut cpu = variant_with(Variant::Cmos65C02, &[0x80, 0x04]);
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.pc, 0x0206);
+        let mut cpu = variant_with(Variant::Cmos65C02, &[0x80, 0xFC]);
+        assert_eq!(step(&mut cpu), 4);
+        assert_eq!(cpu.pc, 0x01FE);
+
+        // STZ $10 and STZ $0300,X
+        let mut cpu = variant_with(Variant::Cmos65C02, &[0x64, 0x10, 0x9E, 0x00, 0x03]);
+        cpu.bus.ram[0x0010] = 0xFF;
+        cpu.bus.ram[0x0302] = 0xFF;
+        cpu.x = 2;
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!((cpu.bus.ram[0x0010], cpu.bus.ram[0x0302]), (0x00, 0x00));
+
+        // JMP ($02FF) reads its high byte from $0300, a cycle longer than the NMOS wrap
+        let mut cpu = variant_with(Variant::Cmos65C02, &[0x6C, 0xFF, 0x02]);
+        cpu.bus.ram[0x02FF] = 0x34;
+        cpu.bus.ram[0x0300] = 0x56;
+        assert_eq!(step(&mut cpu), 6);
+        assert_eq!(cpu.pc, 0x5634);
+
+        // on the 2A03, $80 is a two-byte NOP
+        let mut cpu = cpu_with(&[0x80, 0x04]);
+        assert_eq!(step(&mut cpu), 2);
+        assert_eq!(cpu.pc, 0x0202);
+    }
+
+    #[test]
+    fn logic_and_bit() {
+        let mut cpu = cpu_with(&[0x29, 0x0F, 0x09, 0x80, 0x49, 0xFF]);
+        cpu.a = 0xF0;
+        step(&mut cpu);
+        assert_eq!((cpu.a, cpu.status), (0x00, U | Z));
+        step(&mut cpu);
+        assert_eq!((cpu.a, cpu.status), (0x80, U | N));
+        step(&mut cpu);
+        assert_eq!((cpu.a, cpu.status), (0x7F, U));
+
+        // BIT copies bits 7 and 6 of memory into N and V, Z comes from A & M
+        let mut cpu = cpu_with(&[0x24, 0x10]);
+        cpu.bus.ram[0x0010] = 0xC0;
+        cpu.a = 0x01;
+        step(&mut cpu);
+        assert_eq!((cpu.a, cpu.status), (0x01, U | N | V | Z));
+    }
+
+    #[test]
+    fn compares() {
+        for (opcode, reg, operand, flags) in [(0xC9, 0x10, 0x10, Z | C), (0xE0, 0x10, 0x20, N), (0xC0, 0x20, 0x10, C)] {
+            let mut cpu = cpu_with(&[opcode, operand]);
+            cpu.a = reg;
+            cpu.x = reg;
+            cpu.y = reg;
+            step(&mut cpu);
+            assert_eq!(cpu.status, U | flags, "opcode ${:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn shifts_and_rotates() {
+        // ASL A, LSR A, ROL A, ROR A
+        for (opcode, a, carry, result, flags) in [
+            (0x0A, 0x81, false, 0x02, C),
+            (0x4A, 0x01, false, 0x00, Z | C),
+            (0x2A, 0x80, true, 0x01, C),
+            (0x6A, 0x01, true, 0x80, N | C),
+        ] {
+            let mut cpu = cpu_with(&[opcode]);
+            cpu.a = a;
+            cpu.status |= if carry { C } else { 0 };
+            step(&mut cpu);
+            assert_eq!((cpu.a, cpu.status), (result, U | flags), "opcode ${:02X}", opcode);
+        }
+
+        // ROL $10 works on memory
+        let mut cpu = cpu_with(&[0x26, 0x10]);
+        cpu.bus.ram[0x0010] = 0x40;
+        step(&mut cpu);
+        assert_eq!((cpu.bus.ram[0x0010], cpu.status), (0x80, U | N));
+    }
+
+    #[test]
+    fn increments_and_decrements() {
+        let mut cpu = cpu_with(&[0xE6, 0x10, 0xC6, 0x11, 0xE8, 0xCA, 0xC8, 0x88]);
+        cpu.bus.ram[0x0010] = 0xFF;
+        cpu.bus.ram[0x0011] = 0x00;
+        step(&mut cpu);
+        assert_eq!((cpu.bus.ram[0x0010], cpu.status), (0x00, U | Z));
+        step(&mut cpu);
+        assert_eq!((cpu.bus.ram[0x0011], cpu.status), (0xFF, U | N));
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.x, cpu.status), (0x00, U | Z));
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.y, cpu.status), (0x00, U | Z));
+    }
+
+    #[test]
+    fn flag_instructions() {
+        let mut cpu = cpu_with(&[0x38, 0xF8, 0x78, 0x18, 0xD8, 0x58, 0xB8]);
+        step(&mut cpu);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.status, U | C | D | I);
+        step(&mut cpu);
+        step(&mut cpu);
+        step(&mut cpu);
+        cpu.status |= V;
+        step(&mut cpu);
+        assert_eq!(cpu.status, U);
+    }
+
+    #[test]
+    fn stack_operations() {
+        // PHP pushes B and U, PLP ignores them, PHA and PLA round trip A
+        let mut cpu = cpu_with(&[0x08, 0x28, 0x48, 0xA9, 0x00, 0x68]);
+        cpu.status = U | C | N;
+        step(&mut cpu);
+        assert_eq!((cpu.sp, cpu.bus.ram[0x01FD]), (0xFC, U | B | C | N));
+        cpu.bus.ram[0x01FD] = 0xFF;
+        step(&mut cpu);
+        assert_eq!((cpu.sp, cpu.status), (0xFD, !B));
+
+        cpu.a = 0x80;
+        step(&mut cpu);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.a, cpu.sp), (0x80, 0xFD));
+        assert!(cpu.status & N > 0 && cpu.status & Z == 0);
+    }
+
+    #[test]
+    fn jumps_and_subroutines() {
+        // JSR $0300 pushes the address of its last byte, RTS returns past it
+        let mut cpu = cpu_with(&[0x20, 0x00, 0x03]);
+        cpu.bus.ram[0x0300] = 0x60;
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp), (0x0300, 0xFB));
+        assert_eq!((cpu.bus.ram[0x01FD], cpu.bus.ram[0x01FC]), (0x02, 0x02));
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp), (0x0203, 0xFD));
+
+        // JMP ($02FF) takes its high byte from $0200, not $0300
+        let mut cpu = cpu_with(&[0x6C, 0xFF, 0x02]);
+        cpu.bus.ram[0x02FF] = 0x34;
+        cpu.bus.ram[0x0300] = 0x56;
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x6C34);
+    }
+
+    #[test]
+    fn brk_and_rti() {
+        let mut cpu = cpu_with(&[0x00, 0xEA]);
+        cpu.bus.load(0xFFFE, &[0x00, 0x04]);
+        cpu.bus.ram[0x0400] = 0x40;
+        cpu.status = U | C;
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp, cpu.status), (0x0400, 0xFA, U | C | I));
+        assert_eq!(&cpu.bus.ram[0x01FB..0x01FE], &[U | B | C, 0x02, 0x02]);
+
+        // RTI restores the flags without B and returns past BRK's padding byte
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp, cpu.status), (0x0202, 0xFD, U | C));
+    }
+
+    #[test]
+    fn stores() {
+        let mut cpu = cpu_with(&[0x85, 0x10, 0x86, 0x11, 0x84, 0x12, 0x91, 0x20, 0x81, 0x22]);
+        cpu.a = 0x01;
+        cpu.x = 0x02;
+        cpu.y = 0x03;
+        cpu.bus.load(0x0020, &[0x00, 0x03]);
+        cpu.bus.load(0x0024, &[0x10, 0x03]);
+        for _ in 0..5 {
+            step(&mut cpu);
+        }
+        assert_eq!(&cpu.bus.ram[0x0010..0x0013], &[0x01, 0x02, 0x03]);
+        assert_eq!((cpu.bus.ram[0x0303], cpu.bus.ram[0x0310]), (0x01, 0x01));
+        assert_eq!(cpu.status, U);
+    }
+
+    #[test]
+    fn unofficial_opcodes() {
+        // LAX $10, SAX $11, DCP $12
+        let mut cpu = cpu_with(&[0xA7, 0x10, 0x87, 0x11, 0xC7, 0x12]);
+        cpu.bus.load(0x0010, &[0x8F, 0x00, 0x90]);
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!((cpu.a, cpu.x, cpu.status), (0x8F, 0x8F, U | N));
+
+        cpu.x = 0xF1;
+        assert_eq!(step(&mut cpu), 3);
+        assert_eq!(cpu.bus.ram[0x0011], 0x81);
+
+        // decrements $90 to $8F and compares it with A
+        assert_eq!(step(&mut cpu), 5);
+        assert_eq!((cpu.bus.ram[0x0012], cpu.status), (0x8F, U | Z | C));
+        assert!(cpu.is_unofficial(0xC7, cpu.lookup[0xC7].name));
+    }
+
+    #[test]
+    fn jam_halts_until_reset() {
+        let mut cpu = cpu_with(&[0x02, 0xEA]);
+        assert_eq!(cpu.step_instruction(), StopReason::Halted);
+        assert!(cpu.halted());
+        assert_eq!(cpu.step_cycles(100), StopReason::Halted);
+        assert_eq!(cpu.pc, 0x0201);
+    }
+
+    // flat RAM that logs every access as (address, value, written), with IRQ
+    // sources a test can hold
+    struct RecordingMemory {
+        ram: FlatMemory,
+        log: Vec<(u16, u8, bool)>,
+        irq: u8,
+    }
+
+    impl Memory for RecordingMemory {
+        fn read(&mut self, addr: u16) -> u8 {
+            let data = self.ram.read(addr);
+            self.log.push((addr, data, false));
+            return data;
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.ram.write(addr, data);
+            self.log.push((addr, data, true));
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            return self.ram.peek(addr);
+        }
+
+        fn irq(&self) -> u8 {
+            return self.irq;
+        }
+    }
+
+    // the accesses made by the first instruction of `program` run from $0200
+    fn bus_cycles(program: &[u8], setup: impl FnOnce(&mut CPU<RecordingMemory>)) -> Vec<(u16, u8, bool)> {
+        let mut ram = FlatMemory::new();
+        ram.load(0x0200, program);
+        let mut cpu = CPU::new(RecordingMemory { ram, log: vec![], irq: 0 });
+        cpu.pc = 0x0200;
+        cpu.sp = 0xFD;
+        setup(&mut cpu);
+        assert_eq!(cpu.step_instruction(), StopReason::Done);
+        return cpu.bus.log;
+    }
+
+    #[test]
+    fn dummy_read_on_page_cross() {
+        // LDA $02FF,X with X = 1 first reads $0200, before the high byte is fixed
+        let cycles = bus_cycles(&[0xBD, 0xFF, 0x02], |cpu| cpu.x = 1);
+        assert_eq!(cycles, [
+            (0x0200, 0xBD, false),
+            (0x0201, 0xFF, false),
+            (0x0202, 0x02, false),
+            (0x0200, 0xBD, false),
+            (0x0300, 0x00, false),
+        ]);
+
+        // a store always makes the dummy read, page crossed or not
+        let cycles = bus_cycles(&[0x9D, 0x10, 0x03], |cpu| cpu.a = 0x55);
+        assert_eq!(&cycles[3..], [(0x0310, 0x00, false), (0x0310, 0x55, true)]);
+    }
+
+    #[test]
+    fn read_modify_write_writes_twice() {
+        // INC $10 writes the old value back before the new one
+        let cycles = bus_cycles(&[0xE6, 0x10], |cpu| cpu.bus.ram.ram[0x0010] = 0x41);
+        assert_eq!(cycles, [
+            (0x0200, 0xE6, false),
+            (0x0201, 0x10, false),
+            (0x0010, 0x41, false),
+            (0x0010, 0x41, true),
+            (0x0010, 0x42, true),
+        ]);
+    }
+
+    #[test]
+    fn trace_format() {
+        // JMP $C5F5 at $C000 on a 16 KiB NROM cartridge, run from there once reset
+        let mut rom = ines(1, 0x00, 0x00);
+        rom[16..19].copy_from_slice(&[0x4C, 0xF5, 0xC5]);
+        rom[16 + 0x05F5] = 0xEA;
+        let mut nes = Nes::new(Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(nes.cpu.step_instruction(), StopReason::Done);
+        nes.cpu.pc = 0xC000;
+
+        assert_eq!(nes.cpu.trace_line(),
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7");
+        nes.cpu.step_instruction();
+        assert_eq!(nes.cpu.trace_line(),
+            "C5F5  EA        NOP                             A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10");
+    }
+
+    #[test]
+    fn disassembles_through_peeks() {
+        // LDA #$01, STA $0300,X, LAX ($10),Y, BNE $0202, JMP ($02FF)
+        let program = [0xA9, 0x01, 0x9D, 0x00, 0x03, 0xB3, 0x10, 0xD0, 0xF9, 0x6C, 0xFF, 0x02];
+        let mut ram = FlatMemory::new();
+        ram.load(0x0200, &program);
+        ram.ram[0x02FF] = 0x34;
+        let cpu = CPU::new(RecordingMemory { ram, log: vec![], irq: 0 });
+
+        let instructions = cpu.disassemble(0x0200, 0x0209);
+        let lines: Vec<(u16, usize, String)> = instructions.iter()
+            .map(|d| (d.addr, d.bytes.len(), d.to_ca65().split(';').next().unwrap().trim().to_string()))
+            .collect();
+        assert_eq!(lines, [
+            (0x0200, 2, "lda #$01".to_string()),
+            (0x0202, 3, "sta $0300,X".to_string()),
+            (0x0205, 2, "lax ($10),Y".to_string()),
+            (0x0207, 2, "bne $0202".to_string()),
+            (0x0209, 3, "jmp ($02FF)".to_string()),
+        ]);
+        assert_eq!(instructions[1].to_ca65(), "    sta $0300,X         ; $0202  9D 00 03");
+        assert!(instructions[2].unofficial);
+        // the NMOS page wrap takes the high byte from $0200
+        assert_eq!(instructions[4].target, Some(0xA934));
+        assert!(cpu.bus.log.is_empty());
+    }
+
+    #[test]
+    fn reset_sequence() {
+        let mut cpu = cpu_with(&[]);
+        cpu.bus.load(0xFFFC, &[0x00, 0x04]);
+        cpu.bus.load(0x01FB, &[0xAA, 0xAA, 0xAA]);
+        cpu.a = 0x12;
+        cpu.status = U | C;
+        cpu.reset();
+
+        // the pushes are reads, so the stack is left alone
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!((cpu.pc, cpu.sp, cpu.a, cpu.status), (0x0400, 0xFA, 0x12, U | C | I));
+        assert_eq!(&cpu.bus.ram[0x01FB..0x01FE], &[0xAA, 0xAA, 0xAA]);
+
+        // power on starts from SP = 0, leaving it at $FD
+        cpu.power_on();
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!((cpu.pc, cpu.sp, cpu.a, cpu.status), (0x0400, 0xFD, 0x00, U | I));
+        assert_eq!(cpu.cycles(), 7);
+    }
+
+    // NOPs at $0200, with NMI handled at $0300 and IRQ and BRK at $0400
+    fn cpu_with_vectors(program: &[u8]) -> CPU<FlatMemory> {
+        let mut cpu = cpu_with(program);
+        cpu.bus.load(0x0203, &[0xEA; 8]);
+        cpu.bus.load(0x0300, &[0xEA; 8]);
+        cpu.bus.load(0x0400, &[0xEA; 8]);
+        cpu.bus.load(0xFFFA, &[0x00, 0x03, 0x00, 0x02, 0x00, 0x04]);
+        return cpu;
+    }
+
+    #[test]
+    fn nmi_is_edge_triggered() {
+        let mut cpu = cpu_with_vectors(&[0xEA, 0xEA, 0xEA]);
+        cpu.set_nmi_line(true);
+        step(&mut cpu);
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!((cpu.pc, cpu.sp), (0x0300, 0xFA));
+        // pushed without B, even though I was clear the NMI sets it
+        assert_eq!(cpu.bus.ram[0x01FB], U);
+        assert!(cpu.status & I > 0);
+
+        // holding the line asserted does not fire again
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0302);
+
+        // releasing and asserting it again does
+        cpu.set_nmi_line(false);
+        step(&mut cpu);
+        cpu.set_nmi_line(true);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp), (0x0300, 0xF7));
+    }
+
+    #[test]
+    fn irq_line_is_wired_or() {
+        let mut cpu = cpu_with_vectors(&[0xEA]);
+        cpu.set_irq_line(IrqSource::FrameCounter, true);
+        cpu.set_irq_line(IrqSource::Dmc, true);
+        cpu.set_irq_line(IrqSource::FrameCounter, false);
+        assert!(cpu.irq_line());
+        cpu.set_irq_line(IrqSource::Dmc, false);
+        assert!(!cpu.irq_line());
+
+        // still held by the mapper after the DMC lets go
+        cpu.set_irq_line(IrqSource::Mapper, true);
+        cpu.set_irq_line(IrqSource::Dmc, false);
+        step(&mut cpu);
+        assert_eq!(step(&mut cpu), 7);
+        assert_eq!(cpu.pc, 0x0400);
+        assert_eq!(cpu.bus.ram[0x01FB], U);
+    }
+
+    #[test]
+    fn irq_from_the_bus() {
+        let mut ram = FlatMemory::new();
+        ram.load(0x0200, &[0xEA; 4]);
+        ram.load(0xFFFE, &[0x00, 0x04]);
+        let mut cpu = CPU::new(RecordingMemory { ram, log: vec![], irq: 0 });
+        cpu.pc = 0x0200;
+        cpu.sp = 0xFD;
+        cpu.status = U;
+
+        // a device on the bus holds the line with nothing set on the CPU itself
+        cpu.bus.irq = IrqSource::FrameCounter as u8;
+        cpu.set_irq_line(IrqSource::External, true);
+        cpu.set_irq_line(IrqSource::External, false);
+        assert!(cpu.irq_line());
+        cpu.step_instruction();
+        cpu.step_instruction();
+        assert_eq!(cpu.pc, 0x0400);
+        cpu.bus.irq = 0;
+        assert!(!cpu.irq_line());
+    }
+
+    #[test]
+    fn irq_is_masked_by_i() {
+        let mut cpu = cpu_with_vectors(&[0xEA, 0xEA]);
+        cpu.status |= I;
+        cpu.set_irq_line(IrqSource::External, true);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0202);
+    }
+
+    #[test]
+    fn brk_pushes_b_and_irq_does_not() {
+        let mut cpu = cpu_with_vectors(&[0x00, 0x00]);
+        cpu.status |= C;
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.bus.ram[0x01FB]), (0x0400, U | B | C));
+
+        let mut cpu = cpu_with_vectors(&[0xEA]);
+        cpu.status |= C;
+        cpu.set_irq_line(IrqSource::External, true);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.bus.ram[0x01FB]), (0x0400, U | C));
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        // an NMI seen before BRK pushes the status goes to the NMI vector, but B is
+        // still pushed
+        let mut cpu = cpu_with_vectors(&[0x00, 0x00]);
+        cpu.step_cycles(2);
+        cpu.set_nmi_line(true);
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp), (0x0300, 0xFA));
+        assert_eq!(cpu.bus.ram[0x01FB], U | B);
+        assert_eq!((cpu.bus.ram[0x01FD], cpu.bus.ram[0x01FC]), (0x02, 0x02));
+
+        // one arriving after that is taken once the handler's first instruction has run
+        let mut cpu = cpu_with_vectors(&[0x00, 0x00]);
+        cpu.step_cycles(5);
+        cpu.set_nmi_line(true);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0400);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0401);
+        step(&mut cpu);
+        assert_eq!(cpu.pc, 0x0300);
+    }
+
+    // JSR $0300; NOP at $0200, JSR $0400; RTS at $0300 and NOP; RTS at $0400
+    fn nested_calls() -> CPU<FlatMemory> {
+        let mut cpu = cpu_with(&[0x20, 0x00, 0x03, 0xEA]);
+        cpu.bus.load(0x0300, &[0x20, 0x00, 0x04, 0x60]);
+        cpu.bus.load(0x0400, &[0xEA, 0x60]);
+        return cpu;
+    }
+
+    #[test]
+    fn step_over_runs_whole_calls() {
+        let mut cpu = nested_calls();
+        assert_eq!(cpu.step_over(1000), StopReason::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x0203, 0xFD));
+        // anything else is a single step
+        assert_eq!(cpu.step_over(1000), StopReason::Done);
+        assert_eq!(cpu.pc, 0x0204);
+    }
+
+    #[test]
+    fn step_out_of_nested_calls() {
+        let mut cpu = nested_calls();
+        step(&mut cpu);
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp), (0x0401, 0xF9));
+        assert_eq!(cpu.step_out(1000), StopReason::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x0303, 0xFB));
+        assert_eq!(cpu.step_out(1000), StopReason::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x0203, 0xFD));
+    }
+
+    #[test]
+    fn step_out_across_the_stack_wrap() {
+        // the outer call pushes to $0101 and $0100, the inner one from $01FF
+        let mut cpu = nested_calls();
+        cpu.sp = 0x01;
+        step(&mut cpu);
+        assert_eq!(cpu.sp, 0xFF);
+        assert_eq!(cpu.step_out(1000), StopReason::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x0203, 0x01));
+
+        // from inside the inner call, whose return address is pushed across the wrap
+        let mut cpu = nested_calls();
+        cpu.sp = 0x03;
+        step(&mut cpu);
+        step(&mut cpu);
+        assert_eq!(cpu.sp, 0xFF);
+        step(&mut cpu);
+        assert_eq!(cpu.step_out(1000), StopReason::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x0303, 0x01));
+    }
+
+    #[test]
+    fn step_out_of_an_interrupt_handler() {
+        let mut cpu = cpu_with_vectors(&[0x00, 0x00]);
+        cpu.bus.load(0x0400, &[0xEA, 0xEA, 0x40]);
+        step(&mut cpu);
+        assert_eq!((cpu.pc, cpu.sp), (0x0400, 0xFA));
+        assert_eq!(cpu.step_out(1000), StopReason::Done);
+        assert_eq!((cpu.pc, cpu.sp), (0x0202, 0xFD));
+    }
+
+    #[test]
+    fn waiting_wai_respects_cycle_limits() {
+        let mut memory = FlatMemory::new();
+        memory.load(0x0200, &[0xCB, 0xEA]);
+        let mut cpu = CPU::with_variant(memory, Variant::Cmos65C02);
+        cpu.pc = 0x0200;
+        cpu.sp = 0xFD;
+
+        assert_eq!(cpu.run_until_pc(0x0201, 1000), StopReason::CycleLimit);
+        assert_eq!(cpu.cycles(), 1000);
+        assert!(cpu.waiting());
+        assert_eq!(cpu.step_instruction(), StopReason::Waiting);
+        assert_eq!(cpu.step_over(1000), StopReason::Waiting);
+        assert_eq!(cpu.step_out(1000), StopReason::CycleLimit);
+
+        // an IRQ wakes it even while masked, carrying on after the WAI
+        cpu.status |= I;
+        cpu.set_irq_line(IrqSource::External, true);
+        assert!(!cpu.waiting());
+        assert_eq!(cpu.step_instruction(), StopReason::Done);
+        assert_eq!(cpu.pc, 0x0201);
     }
 }
@@ -0,0 +1,190 @@
+// Decoded 6502 instructions for debuggers and trace output
+
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Relative,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    // 65C02 only
+    ZeroPageIndirect,
+    AbsoluteIndexedIndirect,
+    ZeroPageRelative,
+}
+
+impl AddressingMode {
+    // total instruction length in bytes, including the opcode
+    pub fn size(&self) -> u16 {
+        return match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 1,
+            AddressingMode::Absolute | AddressingMode::AbsoluteX
+                | AddressingMode::AbsoluteY | AddressingMode::Indirect
+                | AddressingMode::AbsoluteIndexedIndirect | AddressingMode::ZeroPageRelative => 3,
+            _ => 2,
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Disassembly {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    pub unofficial: bool,
+    // destination of branches, JMP and JSR, if known
+    pub target: Option<u16>,
+}
+
+impl Disassembly {
+    pub fn new(addr: u16, bytes: Vec<u8>, mnemonic: &'static str, mode: AddressingMode, unofficial: bool) -> Disassembly {
+        let mut disassembly = Disassembly {
+            addr,
+            bytes,
+            mnemonic,
+            mode,
+            unofficial,
+            target: None,
+        };
+        disassembly.target = match mode {
+            AddressingMode::Relative => Some(disassembly.addr.wrapping_add(2).wrapping_add(disassembly.byte_operand() as i8 as u16)),
+            AddressingMode::ZeroPageRelative => {
+                let offset = disassembly.bytes.get(2).copied().unwrap_or(0);
+                Some(disassembly.addr.wrapping_add(3).wrapping_add(offset as i8 as u16))
+            },
+            AddressingMode::Absolute if mnemonic == "JMP" || mnemonic == "JSR" => Some(disassembly.word_operand()),
+            _ => None,
+        };
+        return disassembly;
+    }
+
+    pub fn byte_operand(&self) -> u8 {
+        return self.bytes.get(1).copied().unwrap_or(0);
+    }
+
+    pub fn word_operand(&self) -> u16 {
+        return ((self.bytes.get(2).copied().unwrap_or(0) as u16) << 8) | self.byte_operand() as u16;
+    }
+
+    // operand in standard 6502 assembler syntax, e.g. "($80),Y"
+    pub fn operand(&self) -> String {
+        let byte = self.byte_operand();
+        let word = self.word_operand();
+        return match self.mode {
+            AddressingMode::Implied => String::new(),
+            AddressingMode::Accumulator => String::from("A"),
+            AddressingMode::Immediate => format!("#${:02X}", byte),
+            AddressingMode::ZeroPage => format!("${:02X}", byte),
+            AddressingMode::ZeroPageX => format!("${:02X},X", byte),
+            AddressingMode::ZeroPageY => format!("${:02X},Y", byte),
+            AddressingMode::Relative => format!("${:04X}", self.target.unwrap_or(0)),
+            AddressingMode::Absolute => format!("${:04X}", word),
+            AddressingMode::AbsoluteX => format!("${:04X},X", word),
+            AddressingMode::AbsoluteY => format!("${:04X},Y", word),
+            AddressingMode::Indirect => format!("(${:04X})", word),
+            AddressingMode::IndirectX => format!("(${:02X},X)", byte),
+            AddressingMode::IndirectY => format!("(${:02X}),Y", byte),
+            AddressingMode::ZeroPageIndirect => format!("(${:02X})", byte),
+            AddressingMode::AbsoluteIndexedIndirect => format!("(${:04X},X)", word),
+            AddressingMode::ZeroPageRelative => format!("${:02X},${:04X}", byte, self.target.unwrap_or(0)),
+        };
+    }
+
+    // Renders a line that ca65 assembles back to the same bytes (unofficial
+    // opcodes need `.setcpu "6502X"`, 65C02 code `.setcpu "65C02"`), with the
+    // address and raw bytes as a comment. Opcodes ca65 would assemble to a different
+    // encoding of the same instruction are written out as .byte.
+    pub fn to_ca65(&self) -> String {
+        let instruction = if self.ca65_encodes() {
+            let mut operand = self.operand();
+            let absolute = matches!(self.mode, AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY);
+            if absolute && self.word_operand() < 0x0100 {
+                // stop ca65 from picking the shorter zero page encoding
+                operand = format!("a:{}", operand);
+            }
+            format!("{} {}", ca65_mnemonic(self.mnemonic).to_lowercase(), operand)
+        } else {
+            let bytes: Vec<String> = self.bytes.iter().map(|b| format!("${:02X}", b)).collect();
+            format!(".byte {}", bytes.join(", "))
+        };
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        return format!("    {:<20}; ${:04X}  {}", instruction.trim_end(), self.addr, bytes.join(" "));
+    }
+
+    // false for the duplicate encodings, which ca65 never emits
+    fn ca65_encodes(&self) -> bool {
+        let opcode = self.bytes.first().copied().unwrap_or(0);
+        return match self.mnemonic {
+            "NOP" => matches!(opcode, 0xEA | 0x80 | 0x04 | 0x14 | 0x0C | 0x1C),
+            "SBC" => opcode != 0xEB,
+            "ANC" => opcode == 0x0B,
+            "JAM" => opcode == 0x02,
+            _ => true,
+        };
+    }
+}
+
+// ca65 spells a few unofficial opcodes differently
+// https://cc65.github.io/doc/ca65.html#ss4.4
+fn ca65_mnemonic(mnemonic: &str) -> &str {
+    return match mnemonic {
+        "XAA" => "ANE",
+        "AHX" => "SHA",
+        "LXA" => "LAX",
+        _ => mnemonic,
+    };
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = self.operand();
+        if operand.is_empty() {
+            return write!(f, "{}", self.mnemonic);
+        }
+        return write!(f, "{} {}", self.mnemonic, operand);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ca65(bytes: &[u8], mnemonic: &'static str, mode: AddressingMode) -> String {
+        let line = Disassembly::new(0x8000, bytes.to_vec(), mnemonic, mode, false).to_ca65();
+        return line[..line.find(';').unwrap()].trim().to_string();
+    }
+
+    #[test]
+    fn ca65_canonical_encodings() {
+        assert_eq!(ca65(&[0xEA], "NOP", AddressingMode::Implied), "nop");
+        assert_eq!(ca65(&[0x04, 0x10], "NOP", AddressingMode::ZeroPage), "nop $10");
+        assert_eq!(ca65(&[0xE9, 0x01], "SBC", AddressingMode::Immediate), "sbc #$01");
+        assert_eq!(ca65(&[0xAD, 0x10, 0x00], "LDA", AddressingMode::Absolute), "lda a:$0010");
+    }
+
+    #[test]
+    fn ca65_duplicate_encodings_are_bytes() {
+        assert_eq!(ca65(&[0x1A], "NOP", AddressingMode::Implied), ".byte $1A");
+        assert_eq!(ca65(&[0xEB, 0x01], "SBC", AddressingMode::Immediate), ".byte $EB, $01");
+        assert_eq!(ca65(&[0x2B, 0x01], "ANC", AddressingMode::Immediate), ".byte $2B, $01");
+        assert_eq!(ca65(&[0x12], "JAM", AddressingMode::Implied), ".byte $12");
+    }
+
+    #[test]
+    fn ca65_mnemonics() {
+        assert_eq!(ca65(&[0x8B, 0xFF], "XAA", AddressingMode::Immediate), "ane #$FF");
+        assert_eq!(ca65(&[0x93, 0x10], "AHX", AddressingMode::IndirectY), "sha ($10),Y");
+        assert_eq!(ca65(&[0xAB, 0x00], "LXA", AddressingMode::Immediate), "lax #$00");
+    }
+}
@@ -27,4 +27,10 @@ impl ScreenBuffer{
     pub fn read_pixel(&self, row: usize, col: usize) -> Pixel{
         return self.buffer[row][col];
     }
-}
\ No newline at end of file
+}
+
+impl Default for ScreenBuffer{
+    fn default() -> ScreenBuffer{
+        return ScreenBuffer::new();
+    }
+}
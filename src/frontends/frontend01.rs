@@ -1,13 +1,11 @@
-use std::convert::TryInto;
-
 use glutin_window::GlutinWindow as Window;
 use graphics::{clear, Transformed, rectangle};
 use opengl_graphics::{GlGraphics, OpenGL};
 use piston::event_loop::{EventSettings, Events};
-use piston::input::{RenderArgs, RenderEvent, UpdateArgs, UpdateEvent};
+use piston::input::RenderEvent;
 use piston::window::WindowSettings;
 
-use crate::displays::display::{ScreenBuffer,Pixel};
+use crate::displays::display::ScreenBuffer;
 use super::frontend::Frontend;
 
 pub struct Frontend01{
@@ -50,7 +48,6 @@ impl Frontend for Frontend01{
                                 .transform
                                 .trans(j as f64 * 2., i as f64 * 2.);
                             let pixel = buf.read_pixel(i, j);
-                            let color = [buf.read_pixel(i, j)];
                             rectangle([pixel.r as f32 / 255., pixel.g as f32 / 255., pixel.b as f32 / 255., 1.], square, transform, gl);
                         }
                     }
@@ -0,0 +1,34 @@
+// NES emulator core. `Nes` is the console: load a `Cartridge`, feed it controller
+// input, and call `run_frame` for each picture and its audio. The CPU, bus, PPU and
+// APU it is built from are public too, for debuggers and for running the CPU on its
+// own; the rest is internal.
+
+pub mod apu;
+pub mod breakpoints;
+pub mod bus;
+pub mod cartridge;
+pub mod cpu;
+pub mod disassembler;
+pub mod memory;
+pub mod nes;
+pub mod patch;
+pub mod ppu;
+pub mod rom_db;
+
+mod battery;
+mod controller;
+mod displays;
+mod hash;
+mod mappers;
+mod save_state;
+
+// the windowed frontend, which pulls in piston and OpenGL
+#[cfg(feature = "frontend")]
+pub mod frontends;
+
+pub use crate::bus::RamFill;
+pub use crate::cartridge::{Cartridge, CartridgeError, Header, Mirroring};
+pub use crate::controller::{Button, Controller};
+pub use crate::displays::display::{Pixel, ScreenBuffer};
+pub use crate::nes::{Nes, Region};
+pub use crate::save_state::SaveStateError;
@@ -1,19 +1,34 @@
-mod ppu;
-mod bus;
-mod cpu;
-mod cartridge;
-mod mappers;
-mod displays;
-mod frontends;
-mod apu;
-
-use crate::frontends::{
+use emulator::frontends::{
     frontend::{Frontend},
     frontend01::{Frontend01}
 };
-use crate::displays::display::{ScreenBuffer, Pixel};
+use emulator::{Nes, Pixel, ScreenBuffer};
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+
+    // emulator <rom> runs a game
+    if args.len() == 2 {
+        let mut nes = match Nes::load_rom(&args[1]) {
+            Ok(nes) => nes,
+            Err(e) => {
+                eprintln!("{}: {}", args[1], e);
+                std::process::exit(1);
+            },
+        };
+        let mut frontend = Frontend01::new();
+        frontend.start().unwrap();
+        while frontend.render(nes.run_frame().0).unwrap() {
+            if let Some(e) = nes.take_save_error() {
+                eprintln!("could not save the game: {}", e);
+            }
+        }
+        if let Err(e) = nes.flush_save() {
+            eprintln!("could not save the game: {}", e);
+        }
+        return;
+    }
+
     let mut frontend = Frontend01::new();
     frontend.start().unwrap();
     let mut test_buf = ScreenBuffer::new();
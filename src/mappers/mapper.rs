@@ -1,9 +1,41 @@
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
 pub trait Mapper {
-    fn cpu_map_read(&self, addr: u16) -> Option<u32>;
-    fn cpu_map_write(&self, addr: u16) -> Option<u32>;
+    // Reads may have side effects on the mapper (e.g. latches that switch banks when a
+    // tile is fetched). The peek variants map an address the same way without them,
+    // for debuggers and tracing.
+    fn cpu_map_read(&mut self, addr: u16) -> Option<u32>;
+    fn cpu_map_peek(&self, addr: u16) -> Option<u32>;
+    fn cpu_map_write(&mut self, addr: u16) -> Option<u32>;
+
+    fn ppu_map_read(&mut self, addr: u16) -> Option<u32>;
+    fn ppu_map_peek(&self, addr: u16) -> Option<u32>;
+    fn ppu_map_write(&mut self, addr: u16) -> Option<u32>;
+
+    // PRG RAM at $6000-$7FFF, as an offset into the cartridge's RAM. Most boards
+    // decode it directly; mappers that bank or write-protect it override these.
+    fn prg_ram_map_read(&self, addr: u16) -> Option<u32> {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            return Some((addr & 0x1FFF) as u32);
+        }
+        return None;
+    }
 
-    fn ppu_map_read(&self, addr: u16) -> Option<u32>;
-    fn ppu_map_write(&self, addr: u16) -> Option<u32>;
+    fn prg_ram_map_write(&mut self, addr: u16) -> Option<u32> {
+        return self.prg_ram_map_read(addr);
+    }
+
+    // true while a counter on the board holds the CPU's IRQ line, as on MMC3
+    fn irq(&self) -> bool {
+        return false;
+    }
 
     fn reset(&mut self);
+
+    // bank registers and other internal state, for save states
+    fn save_state(&self, _state: &mut StateWriter) {}
+
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), SaveStateError> {
+        return Ok(());
+    }
 }
\ No newline at end of file
@@ -1,12 +1,12 @@
 use crate::mappers::mapper::Mapper;
 
 pub struct Mapper000 {
-    num_prg_banks: u8,
-    num_chr_banks: u8,
+    num_prg_banks: usize,
+    num_chr_banks: usize,
 }
 
 impl Mapper000 {
-    pub fn new(num_prg_banks: u8, num_chr_banks: u8) -> Mapper000 {
+    pub fn new(num_prg_banks: usize, num_chr_banks: usize) -> Mapper000 {
         return Mapper000 {
             num_prg_banks,
             num_chr_banks,
@@ -15,8 +15,12 @@ impl Mapper000 {
 }
 
 impl Mapper for Mapper000 {
-    fn cpu_map_read(&self, addr: u16) -> Option<u32> {
-        if addr >= 0x8000 && addr <= 0xFFFF {
+    fn cpu_map_read(&mut self, addr: u16) -> Option<u32> {
+        return self.cpu_map_peek(addr);
+    }
+
+    fn cpu_map_peek(&self, addr: u16) -> Option<u32> {
+        if addr >= 0x8000 {
             if self.num_prg_banks > 1 {
                 return Some((addr & 0x7FFF) as u32);
             } else {
@@ -27,20 +31,25 @@ impl Mapper for Mapper000 {
         return None;
     }
 
-    fn cpu_map_write(&self, addr: u16) -> Option<u32> {
-        return self.cpu_map_read(addr);
+    // NROM has no registers and its PRG is ROM
+    fn cpu_map_write(&mut self, _addr: u16) -> Option<u32> {
+        return None;
+    }
+
+    fn ppu_map_read(&mut self, addr: u16) -> Option<u32> {
+        return self.ppu_map_peek(addr);
     }
 
-    fn ppu_map_read(&self, addr: u16) -> Option<u32> {
-        if addr >= 0x0000 && addr <= 0x1FFF {
+    fn ppu_map_peek(&self, addr: u16) -> Option<u32> {
+        if addr <= 0x1FFF {
             return Some(addr as u32);
         }
 
         return None;
     }
 
-    fn ppu_map_write(&self, addr: u16) -> Option<u32> {
-        if addr >= 0x0000 && addr <= 0x1FFF && self.num_chr_banks == 0 {
+    fn ppu_map_write(&mut self, addr: u16) -> Option<u32> {
+        if addr <= 0x1FFF && self.num_chr_banks == 0 {
             return Some(addr as u32);
         }
 
@@ -1,10 +1,13 @@
+use crate::cartridge::{CartridgeError, Header};
 use crate::mappers::mapper::Mapper;
 use crate::mappers::mapper_000::Mapper000;
 
-pub fn create_mapper(mapper_id: u8, num_prg_banks: u8, num_chr_banks: u8) -> Box<dyn Mapper> {
-    if mapper_id == 0 {
-        return Box::new(Mapper000::new(num_prg_banks, num_chr_banks));
+pub fn create_mapper(header: &Header) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let num_prg_banks = header.prg_rom_size / 16384;
+    let num_chr_banks = header.chr_rom_size / 8192;
+    if header.mapper == 0 {
+        return Ok(Box::new(Mapper000::new(num_prg_banks, num_chr_banks)));
     } else {
-        panic!("Unknown mapper of id {}", mapper_id);
+        return Err(CartridgeError::UnsupportedMapper(header.mapper));
     }
 }
\ No newline at end of file
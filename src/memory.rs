@@ -0,0 +1,73 @@
+// The address space seen by the CPU
+
+pub trait Memory {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+
+    // reads without side effects, for debuggers, disassembly and tracing
+    fn peek(&self, addr: u16) -> u8;
+
+    // (scanline, dot) of the PPU sharing this address space, shown in trace output
+    fn ppu_position(&self) -> (i16, u16) {
+        return (0, 0);
+    }
+
+    // VRAM address the next PPUDATA ($2007) access will use, for VRAM breakpoints
+    fn ppu_vram_address(&self) -> Option<u16> {
+        return None;
+    }
+
+    // called at the end of every CPU cycle, so devices clocked alongside the CPU keep pace
+    fn tick(&mut self) {}
+
+    // level of the NMI line as driven by devices on this bus, such as the PPU at vblank
+    fn nmi(&self) -> bool {
+        return false;
+    }
+
+    // IrqSource bits of the devices on this bus holding the IRQ line low, such as the
+    // APU frame counter or a mapper's scanline counter. The CPU ORs them with its own.
+    fn irq(&self) -> u8 {
+        return 0;
+    }
+}
+
+// 64 KiB of plain RAM, for running 6502 programs outside of the NES memory map
+pub struct FlatMemory {
+    pub ram: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new() -> FlatMemory {
+        return FlatMemory {
+            ram: vec![0x00; 0x10000],
+        }
+    }
+
+    // copies `data` into RAM starting at `addr`
+    pub fn load(&mut self, addr: u16, data: &[u8]) {
+        let start = addr as usize;
+        let end = (start + data.len()).min(self.ram.len());
+        self.ram[start..end].copy_from_slice(&data[..end - start]);
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> FlatMemory {
+        return FlatMemory::new();
+    }
+}
+
+impl Memory for FlatMemory {
+    fn read(&mut self, addr: u16) -> u8 {
+        return self.ram[addr as usize];
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        return self.ram[addr as usize];
+    }
+}
@@ -0,0 +1,463 @@
+// The console: owns the CPU and, through its bus, the PPU, APU, controllers and
+// cartridge, and runs them together a frame at a time
+
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::apu::APU;
+use crate::battery;
+use crate::breakpoints::BreakpointHit;
+use crate::bus::{Bus, RamFill};
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::controller::Controller;
+use crate::cpu::CPU;
+use crate::displays::display::ScreenBuffer;
+use crate::ppu::PPU;
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl Region {
+    // master clock ticks per PPU dot and per CPU cycle, giving 3 dots per CPU
+    // cycle on NTSC and 3.2 on PAL
+    // https://www.nesdev.org/wiki/Cycle_reference_chart
+    pub fn ppu_divider(&self) -> u64 {
+        return match self {
+            Region::Ntsc => 4,
+            Region::Pal => 5,
+        };
+    }
+
+    pub fn cpu_divider(&self) -> u64 {
+        return match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+        };
+    }
+
+    // master clock frequency in Hz
+    pub fn master_clock_rate(&self) -> u64 {
+        return match self {
+            Region::Ntsc => 21_477_272,
+            Region::Pal => 26_601_712,
+        };
+    }
+
+    // scanlines per frame, including the pre-render line
+    pub fn scanlines(&self) -> i16 {
+        return match self {
+            Region::Ntsc => 262,
+            Region::Pal => 312,
+        };
+    }
+}
+
+pub struct Nes {
+    pub cpu: CPU<Bus>,
+    region: Region,
+    // contents of work RAM after power_cycle
+    pub ram_fill: RamFill,
+
+    // where battery-backed RAM is saved, and frames run since it last was
+    save_path: Option<PathBuf>,
+    frames_since_save: u32,
+    save_error: Option<io::Error>,
+
+    // run_frame_until_break stopped before the frame was finished
+    mid_frame: bool,
+}
+
+// battery RAM that has changed is written out this often while running
+const SAVE_INTERVAL_FRAMES: u32 = 300;
+
+impl Nes {
+    // runs at the region the cartridge's header asks for
+    pub fn new(cartridge: Cartridge) -> Nes {
+        let region = cartridge.header().region;
+        return Nes::with_region(cartridge, region);
+    }
+
+    pub fn with_region(cartridge: Cartridge, region: Region) -> Nes {
+        let mut bus = Bus::new(cartridge, APU::new(), PPU::new());
+        bus.set_region(region);
+        let mut nes = Nes {
+            cpu: CPU::new(bus),
+            region,
+            ram_fill: RamFill::Zero,
+            save_path: None,
+            frames_since_save: 0,
+            save_error: None,
+            mid_frame: false,
+        };
+        nes.power_cycle();
+        return nes;
+    }
+
+    // Loads a ROM file. A battery-backed game's RAM is restored from the .sav file
+    // beside it, which is kept up to date from then on.
+    pub fn load_rom<P: AsRef<Path>>(rom_path: P) -> Result<Nes, CartridgeError> {
+        let cartridge = Cartridge::new(rom_path.as_ref())?;
+        let mut nes = Nes::new(cartridge);
+        if nes.cpu.bus.cartridge.battery_ram().is_some() {
+            nes.set_save_path(Some(battery::sav_path(rom_path.as_ref())))?;
+        }
+        return Ok(nes);
+    }
+
+    // Sets the .sav file for battery RAM and loads it if it exists; None stops saving
+    pub fn set_save_path(&mut self, path: Option<PathBuf>) -> io::Result<()> {
+        if let Some(path) = &path {
+            if let Some(data) = battery::read(path)? {
+                self.cpu.bus.cartridge.load_battery_ram(&data);
+            }
+        }
+        self.save_path = path;
+        self.frames_since_save = 0;
+        return Ok(());
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        return self.save_path.as_deref();
+    }
+
+    // Writes battery RAM to the .sav file if it changed since the last save
+    pub fn flush_save(&mut self) -> io::Result<()> {
+        self.frames_since_save = 0;
+        let cartridge = &mut self.cpu.bus.cartridge;
+        if let (Some(path), Some(data)) = (&self.save_path, cartridge.battery_ram()) {
+            if cartridge.battery_ram_dirty() {
+                battery::write_atomic(path, data)?;
+                cartridge.mark_battery_ram_saved();
+            }
+        }
+        return Ok(());
+    }
+
+    // the error from the last failed periodic save, which is retried on the next one
+    pub fn take_save_error(&mut self) -> Option<io::Error> {
+        return self.save_error.take();
+    }
+
+    pub fn region(&self) -> Region {
+        return self.region;
+    }
+
+    pub fn bus(&self) -> &Bus {
+        return &self.cpu.bus;
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        return &mut self.cpu.bus;
+    }
+
+    // port 0 is $4016, port 1 is $4017
+    pub fn controller(&mut self, port: usize) -> &mut Controller {
+        return &mut self.cpu.bus.controllers[port];
+    }
+
+    // switches the console off and on again
+    pub fn power_cycle(&mut self) {
+        self.cpu.bus.power_on(self.ram_fill);
+        self.cpu.power_on();
+    }
+
+    // presses the reset button
+    pub fn reset(&mut self) {
+        self.cpu.bus.reset();
+        self.cpu.reset();
+    }
+
+    // Runs until the PPU finishes its current frame, returning the picture and the
+    // audio produced meanwhile. Breakpoints do not stop it; debuggers use
+    // run_frame_until_break instead.
+    pub fn run_frame(&mut self) -> (&ScreenBuffer, Vec<f32>) {
+        self.start_frame();
+        while !self.cpu.bus.ppu.frame_complete {
+            self.cpu.clock();
+        }
+        return self.end_frame();
+    }
+
+    // Like run_frame, but returns as soon as a breakpoint fires, leaving the frame
+    // part run. The next call carries on from there, and the picture and audio come
+    // back once the frame is finished.
+    pub fn run_frame_until_break(&mut self) -> Result<(&ScreenBuffer, Vec<f32>), BreakpointHit> {
+        self.start_frame();
+        while !self.cpu.bus.ppu.frame_complete {
+            self.cpu.clock();
+            if let Some(hit) = self.cpu.take_breakpoint_hit() {
+                self.mid_frame = true;
+                return Err(hit);
+            }
+        }
+        return Ok(self.end_frame());
+    }
+
+    fn start_frame(&mut self) {
+        if !std::mem::take(&mut self.mid_frame) {
+            self.cpu.bus.ppu.frame_complete = false;
+        }
+    }
+
+    fn end_frame(&mut self) -> (&ScreenBuffer, Vec<f32>) {
+        self.frames_since_save += 1;
+        if self.frames_since_save >= SAVE_INTERVAL_FRAMES {
+            if let Err(e) = self.flush_save() {
+                self.save_error = Some(e);
+            }
+        }
+        let samples = self.cpu.bus.apu.take_samples();
+        return (self.cpu.bus.ppu.screen(), samples);
+    }
+
+    // Snapshots the whole console. It can only be loaded back into a console of the
+    // same region running the same cartridge.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        state.u32(self.cpu.bus.cartridge.crc32());
+        self.cpu.save_state(&mut state);
+        self.cpu.bus.save_state(&mut state);
+        return state.finish();
+    }
+
+    // On error the console is left as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let backup = self.save_state();
+        let result = self.restore(data);
+        if result.is_err() {
+            self.restore(&backup).expect("a state just saved should load");
+        }
+        return result;
+    }
+
+    fn restore(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut state = StateReader::new(data)?;
+        // checked first, so a state for another game is a mismatch rather than corrupt
+        if state.u32()? != self.cpu.bus.cartridge.crc32() {
+            return Err(SaveStateError::Mismatch);
+        }
+        self.cpu.load_state(&mut state)?;
+        self.cpu.bus.load_state(&mut state)?;
+        return state.finish();
+    }
+
+    // the picture as drawn so far; complete straight after run_frame
+    pub fn screen(&self) -> &ScreenBuffer {
+        return self.cpu.bus.ppu.screen();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        return self.cpu.bus.apu.sample_rate();
+    }
+}
+
+// saves the game when the console is dropped, the equivalent of switching it off
+impl Drop for Nes {
+    fn drop(&mut self) {
+        let _ = self.flush_save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breakpoints::Breakpoint;
+    use crate::cartridge::tests::{ines, with_trainer};
+    use crate::cpu::StopReason;
+
+    // an NROM game that spins on NOP; JMP $C000
+    fn spinning_cartridge() -> Cartridge {
+        let mut rom = ines(1, 0x00, 0x00);
+        rom[16..20].copy_from_slice(&[0xEA, 0x4C, 0x00, 0xC0]);
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        return Cartridge::from_bytes(&rom).unwrap();
+    }
+
+    fn spinning() -> Nes {
+        return Nes::new(spinning_cartridge());
+    }
+
+    #[test]
+    fn master_clock_per_region() {
+        // (region, master clock ticks per CPU cycle, PPU dots per 5 CPU cycles)
+        for (region, divider, dots) in [(Region::Ntsc, 12, 15), (Region::Pal, 16, 16)] {
+            let mut nes = Nes::with_region(spinning_cartridge(), region);
+            let (cycles, master) = (nes.cpu.cycles(), nes.bus().master_clock());
+            let position = nes.bus().ppu.cycle;
+            assert_eq!(nes.cpu.step_cycles(5), StopReason::Done);
+            assert_eq!(nes.cpu.cycles() - cycles, 5);
+            assert_eq!(nes.bus().master_clock() - master, 5 * divider);
+            assert_eq!(nes.bus().ppu.cycle - position, dots);
+        }
+    }
+
+    #[test]
+    fn step_scanline_and_frame() {
+        let mut nes = spinning();
+        nes.run_frame();
+        let scanline = nes.bus().ppu.scanline;
+        assert_eq!(nes.cpu.step_scanline(), StopReason::Done);
+        assert_eq!(nes.bus().ppu.scanline, scanline + 1);
+        // stopped at the first instruction boundary, which is at most 5 cycles in
+        assert!(nes.bus().ppu.cycle <= 15);
+        assert!(nes.cpu.complete());
+
+        // a frame later it is just past the start of the pre-render line
+        assert_eq!(nes.cpu.step_frame(), StopReason::Done);
+        assert_eq!(nes.bus().ppu.scanline, -1);
+        assert!(nes.bus().ppu.cycle <= 15);
+        let start = nes.cpu.cycles();
+        assert_eq!(nes.cpu.step_frame(), StopReason::Done);
+        assert_eq!(nes.bus().ppu.scanline, -1);
+        assert!((29775..=29785).contains(&(nes.cpu.cycles() - start)));
+    }
+
+    #[test]
+    fn run_frame_until_break_stops_at_every_hit() {
+        let mut nes = spinning();
+        nes.run_frame();
+        let id = nes.cpu.add_breakpoint(Breakpoint::Execute(0xC001));
+
+        // the loop takes 5 cycles, so a frame passes the breakpoint thousands of times
+        let mut hits = 0;
+        loop {
+            match nes.run_frame_until_break() {
+                Ok(_) => break,
+                Err(hit) => {
+                    assert_eq!((hit.id, hit.addr), (id, 0xC001));
+                    assert_eq!(nes.cpu.pc, 0xC001);
+                    hits += 1;
+                },
+            }
+        }
+        assert!(hits > 5000, "{} hits", hits);
+
+        // a frame that was not interrupted is a whole one
+        let start = nes.cpu.cycles();
+        nes.cpu.clear_breakpoints();
+        assert!(nes.run_frame_until_break().is_ok());
+        assert!((29780..=29781).contains(&(nes.cpu.cycles() - start)));
+    }
+
+    #[test]
+    fn run_frame_ignores_breakpoints() {
+        let mut nes = spinning();
+        nes.run_frame();
+        nes.cpu.add_breakpoint(Breakpoint::Execute(0xC001));
+        let start = nes.cpu.cycles();
+        nes.run_frame();
+        assert!((29780..=29781).contains(&(nes.cpu.cycles() - start)));
+        assert!(nes.cpu.take_breakpoint_hit().is_some());
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut nes = spinning();
+        nes.run_frame();
+        nes.bus_mut().write(0x0010, 0x42);
+        let state = nes.save_state();
+        nes.run_frame();
+        let after = nes.save_state();
+
+        // loading goes back to where the state was saved, and runs on the same way
+        nes.bus_mut().write(0x0010, 0x00);
+        nes.run_frame();
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.save_state(), state);
+        assert_eq!(nes.bus_mut().read(0x0010), 0x42);
+        nes.run_frame();
+        assert_eq!(nes.save_state(), after);
+    }
+
+    #[test]
+    fn bad_save_states_are_rejected() {
+        let mut nes = spinning();
+        nes.run_frame();
+        let state = nes.save_state();
+        nes.run_frame();
+        let current = nes.save_state();
+
+        // a state cut short anywhere fails and leaves the console as it was
+        for len in (0..state.len()).step_by(7).chain([state.len() - 1]) {
+            let error = nes.load_state(&state[..len]).unwrap_err();
+            let expected = if len < 5 { SaveStateError::BadHeader } else { SaveStateError::Truncated };
+            assert_eq!(error, expected, "{} bytes", len);
+            assert_eq!(nes.save_state(), current);
+        }
+        let mut long = state.clone();
+        long.push(0);
+        assert_eq!(nes.load_state(&long), Err(SaveStateError::Invalid));
+
+        let mut corrupt = state.clone();
+        corrupt[0] = b'X';
+        assert_eq!(nes.load_state(&corrupt), Err(SaveStateError::BadHeader));
+        let mut corrupt = state.clone();
+        corrupt[4] += 1;
+        assert_eq!(nes.load_state(&corrupt), Err(SaveStateError::BadHeader));
+
+        // the CPU's step, 8 bytes in after the header and cartridge checksum, and the
+        // address step, which cannot be past it
+        let mut corrupt = state.clone();
+        corrupt[17] = 200;
+        assert_eq!(nes.load_state(&corrupt), Err(SaveStateError::Invalid));
+        let mut corrupt = state.clone();
+        corrupt[17] = 1;
+        corrupt[39..41].copy_from_slice(&[1, 2]);
+        assert_eq!(nes.load_state(&corrupt), Err(SaveStateError::Invalid));
+        assert_eq!(nes.save_state(), current);
+
+        // the I/O latch cannot have been refreshed in a later frame
+        nes.bus_mut().ppu.frame_count = 10;
+        nes.bus_mut().write(0x2000, 0x00);
+        nes.bus_mut().ppu.frame_count = 5;
+        let corrupt = nes.save_state();
+        nes.bus_mut().ppu.frame_count = 10;
+        assert_eq!(nes.load_state(&corrupt), Err(SaveStateError::Invalid));
+    }
+
+    #[test]
+    fn save_states_only_load_into_the_same_console() {
+        let state = spinning().save_state();
+
+        let mut rom = ines(1, 0x00, 0x00);
+        rom[16] = 0xEA;
+        let mut other = Nes::new(Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(other.load_state(&state), Err(SaveStateError::Mismatch));
+
+        let mut pal = Nes::with_region(spinning_cartridge(), Region::Pal);
+        assert_eq!(pal.load_state(&state), Err(SaveStateError::Mismatch));
+    }
+
+    #[test]
+    fn battery_save_keeps_the_trainer() {
+        let dir = std::env::temp_dir().join(format!("emulator-battery-trainer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // NROM with battery-backed RAM and a trainer, saved with RAM all $55
+        let mut rom = with_trainer();
+        rom[6] |= 0x02;
+        std::fs::write(dir.join("game.nes"), &rom).unwrap();
+        std::fs::write(dir.join("game.sav"), [0x55; 0x2000]).unwrap();
+
+        let mut nes = Nes::load_rom(dir.join("game.nes")).unwrap();
+        assert_eq!(nes.save_path(), Some(dir.join("game.sav").as_path()));
+        assert_eq!(nes.bus_mut().read(0x6FFF), 0x55);
+        assert_eq!(nes.bus_mut().read(0x7001), 0x01);
+        assert_eq!(nes.bus_mut().read(0x71FF), 0xFF);
+        assert_eq!(nes.bus_mut().read(0x7200), 0x55);
+
+        // a write reaches the file once flushed, and is there the next time
+        nes.bus_mut().write(0x6000, 0x12);
+        nes.flush_save().unwrap();
+        drop(nes);
+        assert_eq!(std::fs::read(dir.join("game.sav")).unwrap()[0], 0x12);
+        let mut nes = Nes::load_rom(dir.join("game.nes")).unwrap();
+        assert_eq!(nes.bus_mut().read(0x6000), 0x12);
+        drop(nes);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,473 @@
+// Soft-patching: applies IPS, UPS and BPS patches to a ROM image before it is parsed,
+// so translations and hacks can be played without modifying the original ROM
+
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use crate::hash::crc32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl fmt::Display for PatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PatchFormat::Ips => "IPS",
+            PatchFormat::Ups => "UPS",
+            PatchFormat::Bps => "BPS",
+        };
+        return write!(f, "{}", name);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PatchError {
+    // the patch does not start with a known magic number
+    UnknownFormat,
+    // the patch ends early or points outside the data it patches
+    Malformed(PatchFormat),
+    // the ROM is not the one the patch was made for
+    SourceSize { expected: usize, actual: usize },
+    SourceChecksum { expected: u32, actual: u32 },
+    // the patched ROM, or the patch itself, is corrupt
+    TargetChecksum { expected: u32, actual: u32 },
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Malformed(format) => write!(f, "{} patch is corrupt", format),
+            PatchError::SourceSize { expected, actual } =>
+                write!(f, "patch is for a ROM of {} bytes, but this ROM is {} bytes", expected, actual),
+            PatchError::SourceChecksum { expected, actual } =>
+                write!(f, "patch is for a ROM with CRC32 {:08X}, but this ROM's is {:08X}", expected, actual),
+            PatchError::TargetChecksum { expected, actual } =>
+                write!(f, "patched ROM has CRC32 {:08X}, expected {:08X}", actual, expected),
+            PatchError::PatchChecksum { expected, actual } =>
+                write!(f, "patch file has CRC32 {:08X}, expected {:08X}", actual, expected),
+        };
+    }
+}
+
+impl error::Error for PatchError {}
+
+// Larger than any NES ROM; a patch's target size is checked against this before
+// anything is allocated
+const MAX_TARGET_SIZE: usize = 64 << 20;
+
+// Patch extensions looked for beside a ROM, in order of preference
+const EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// a patch with the ROM's name, e.g. game.ips for game.nes
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    return EXTENSIONS.iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file());
+}
+
+pub fn format(patch: &[u8]) -> Option<PatchFormat> {
+    if patch.starts_with(b"PATCH") {
+        return Some(PatchFormat::Ips);
+    } else if patch.starts_with(b"UPS1") {
+        return Some(PatchFormat::Ups);
+    } else if patch.starts_with(b"BPS1") {
+        return Some(PatchFormat::Bps);
+    }
+    return None;
+}
+
+// Returns the patched ROM, detecting the format from the patch's magic number
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    return match format(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Ups) => apply_ups(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    };
+}
+
+// Reads a patch front to back, failing with Malformed at its end
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: PatchFormat,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize, format: PatchFormat) -> PatchReader<'a> {
+        return PatchReader { data, pos, format };
+    }
+
+    fn malformed(&self) -> PatchError {
+        return PatchError::Malformed(self.format);
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        if self.data.len() - self.pos < len {
+            return Err(self.malformed());
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Ok(slice);
+    }
+
+    fn u8(&mut self) -> Result<u8, PatchError> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    // big endian, as IPS uses
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        return Ok(self.bytes(len)?.iter().fold(0, |value, &b| (value << 8) | b as usize));
+    }
+
+    // UPS and BPS variable-length numbers: 7 bits per byte, least significant first,
+    // with the top bit marking the last byte and each continuation adding one
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.u8()?;
+            value = ((byte & 0x7F) as usize).checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or(self.malformed())?;
+            if byte & 0x80 > 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or(self.malformed())?;
+            value = value.checked_add(shift).ok_or(self.malformed())?;
+        }
+    }
+}
+
+// IPS: records of a 24-bit offset and 16-bit length followed by the data, or by a
+// length of 0, a 16-bit count and one byte to repeat (RLE). "EOF" ends the records
+// and may be followed by a 24-bit size to truncate the output to.
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = PatchReader::new(patch, 5, PatchFormat::Ips);
+    loop {
+        let offset = reader.be(3)?;
+        if offset == 0x454F46 {
+            break;
+        }
+        let len = reader.be(2)?;
+        let (len, rle) = if len == 0 {
+            (reader.be(2)?, Some(reader.u8()?))
+        } else {
+            (len, None)
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0x00);
+        }
+        match rle {
+            Some(value) => out[offset..offset + len].iter_mut().for_each(|b| *b = value),
+            None => out[offset..offset + len].copy_from_slice(reader.bytes(len)?),
+        }
+    }
+    if patch.len() - reader.pos >= 3 {
+        let size = reader.be(3)?;
+        out.truncate(size);
+    }
+    return Ok(out);
+}
+
+// checks the three CRC32s that end UPS and BPS patches: source, target and patch
+fn footer(patch: &[u8], format: PatchFormat) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 16 {
+        return Err(PatchError::Malformed(format));
+    }
+    let crc = |i: usize| {
+        let b = &patch[patch.len() - 12 + i * 4..];
+        return u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    };
+    let actual = crc32(&patch[..patch.len() - 4]);
+    if actual != crc(2) {
+        return Err(PatchError::PatchChecksum { expected: crc(2), actual });
+    }
+    return Ok((crc(0), crc(1)));
+}
+
+fn check_source(rom: &[u8], size: usize, checksum: u32) -> Result<(), PatchError> {
+    if rom.len() != size {
+        return Err(PatchError::SourceSize { expected: size, actual: rom.len() });
+    }
+    let actual = crc32(rom);
+    if actual != checksum {
+        return Err(PatchError::SourceChecksum { expected: checksum, actual });
+    }
+    return Ok(());
+}
+
+fn check_target(out: &[u8], checksum: u32) -> Result<(), PatchError> {
+    let actual = crc32(out);
+    if actual != checksum {
+        return Err(PatchError::TargetChecksum { expected: checksum, actual });
+    }
+    return Ok(());
+}
+
+// UPS: hunks of a relative offset followed by bytes XORed into the source, each
+// run ending with a 0 byte
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = footer(patch, PatchFormat::Ups)?;
+    let end = patch.len() - 12;
+    let mut reader = PatchReader::new(&patch[..end], 4, PatchFormat::Ups);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(reader.malformed());
+    }
+    check_source(rom, source_size, source_crc)?;
+
+    let mut out = rom.to_vec();
+    out.resize(target_size, 0x00);
+    let mut pos: usize = 0;
+    while reader.pos < end {
+        pos = pos.checked_add(reader.varint()?).ok_or(reader.malformed())?;
+        loop {
+            let x = reader.u8()?;
+            if pos < out.len() {
+                out[pos] ^= x;
+            }
+            pos = pos.checked_add(1).ok_or(reader.malformed())?;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    check_target(&out, target_crc)?;
+    return Ok(out);
+}
+
+// BPS: the target is built from four actions: copying from the same offset in the
+// source, copying bytes stored in the patch, and copying from anywhere in the source
+// or in the target written so far
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = footer(patch, PatchFormat::Bps)?;
+    let end = patch.len() - 12;
+    let mut reader = PatchReader::new(&patch[..end], 4, PatchFormat::Bps);
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(reader.malformed());
+    }
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    check_source(rom, source_size, source_crc)?;
+
+    let mut out = Vec::with_capacity(target_size);
+    let mut source_offset: usize = 0;
+    let mut target_offset: usize = 0;
+    while reader.pos < end {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        if out.len().checked_add(len).is_none_or(|size| size > target_size) {
+            return Err(reader.malformed());
+        }
+        match data & 0x03 {
+            // SourceRead
+            0 => {
+                let start = out.len();
+                let bytes = rom.get(start..start + len).ok_or(reader.malformed())?;
+                out.extend_from_slice(bytes);
+            },
+            // TargetRead
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_offset = relative(source_offset, reader.varint()?).ok_or(reader.malformed())?;
+                let source_end = source_offset.checked_add(len).ok_or(reader.malformed())?;
+                let bytes = rom.get(source_offset..source_end).ok_or(reader.malformed())?;
+                out.extend_from_slice(bytes);
+                source_offset = source_end;
+            },
+            // TargetCopy, which may overlap the bytes it is writing
+            _ => {
+                target_offset = relative(target_offset, reader.varint()?).ok_or(reader.malformed())?;
+                if target_offset >= out.len() {
+                    return Err(reader.malformed());
+                }
+                for _ in 0..len {
+                    out.push(out[target_offset]);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+    if out.len() != target_size {
+        return Err(reader.malformed());
+    }
+    check_target(&out, target_crc)?;
+    return Ok(out);
+}
+
+// BPS copy offsets are stored as a sign in bit 0 and a distance above it
+fn relative(offset: usize, data: usize) -> Option<usize> {
+    if data & 1 > 0 {
+        return offset.checked_sub(data >> 1);
+    }
+    return offset.checked_add(data >> 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let low = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(0x80 | low);
+                return bytes;
+            }
+            bytes.push(low);
+            value -= 1;
+        }
+    }
+
+    // a UPS or BPS patch: the magic number, the sizes and body, then the checksums
+    fn patch(magic: &[u8], source: &[u8], target: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut patch = magic.to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend_from_slice(body);
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        return patch;
+    }
+
+    #[test]
+    fn varint_encoding() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 1 << 20, usize::MAX] {
+            let bytes = varint(value);
+            assert_eq!(PatchReader::new(&bytes, 0, PatchFormat::Ups).varint(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn ips_records() {
+        let mut patch = b"PATCH".to_vec();
+        // two bytes at 2, then four $CC at 6, running past the end of the ROM
+        patch.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x04, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&[0x11; 8], &patch), Ok(vec![0x11, 0x11, 0xAA, 0xBB, 0x11, 0x11, 0xCC, 0xCC, 0xCC, 0xCC]));
+
+        // the size after EOF truncates the output
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply(&[0x11; 8], &patch), Ok(vec![0x11, 0x11, 0xAA, 0xBB]));
+    }
+
+    #[test]
+    fn ips_malformed() {
+        // IPS has no checksums, so a cut-off patch is all that can be caught
+        let patch = b"PATCH\x00\x00\x02\x00\x04\xAA\xBB";
+        assert_eq!(apply(&[0x11; 8], patch), Err(PatchError::Malformed(PatchFormat::Ips)));
+        assert_eq!(apply(&[0x11; 8], b"PATCH"), Err(PatchError::Malformed(PatchFormat::Ips)));
+    }
+
+    #[test]
+    fn ups() {
+        let source = [0x01, 0x02, 0x03, 0x04];
+        let target = [0x01, 0x09, 0x03, 0x04, 0x05];
+        // XOR $0B into byte 1; then, one past that hunk's terminator, $05 into byte 4
+        let patch = patch(b"UPS1", &source, &target, &[0x81, 0x0B, 0x00, 0x81, 0x05, 0x00]);
+        assert_eq!(apply(&source, &patch), Ok(target.to_vec()));
+
+        assert_eq!(apply(&source[..3], &patch), Err(PatchError::SourceSize { expected: 4, actual: 3 }));
+        assert_eq!(apply(&[0x01, 0x02, 0x03, 0x05], &patch),
+            Err(PatchError::SourceChecksum { expected: crc32(&source), actual: crc32(&[0x01, 0x02, 0x03, 0x05]) }));
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 0xFF;
+        assert!(matches!(apply(&source, &corrupt), Err(PatchError::PatchChecksum { .. })));
+    }
+
+    #[test]
+    fn ups_target_size_is_capped() {
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(4));
+        patch.extend(varint(usize::MAX >> 8));
+        patch.extend_from_slice(&[0x00; 8]);
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        assert_eq!(apply(&[0x00; 4], &patch), Err(PatchError::Malformed(PatchFormat::Ups)));
+    }
+
+    #[test]
+    fn bps() {
+        let source = [0x01, 0x02, 0x03, 0x04];
+        let target = [0x01, 0x02, 0x09, 0x09, 0x09, 0x01, 0x02];
+        let body = [
+            0x80,
+            // SourceRead 2
+            0x80 | (1 << 2),
+            // TargetRead 1: $09
+            0x80 | 1, 0x09,
+            // TargetCopy 2 from 2, overlapping itself
+            0x80 | (1 << 2) | 3, 0x80 | (2 << 1),
+            // SourceCopy 2 from 0
+            0x80 | (1 << 2) | 2, 0x80,
+        ];
+        let patch = patch(b"BPS1", &source, &target, &body);
+        assert_eq!(apply(&source, &patch), Ok(target.to_vec()));
+
+        assert!(matches!(apply(&[0x01, 0x02, 0x03, 0x05], &patch), Err(PatchError::SourceChecksum { .. })));
+        // a patch whose actions do not produce the target it promises
+        let mut wrong = patch[..patch.len() - 8].to_vec();
+        wrong.extend_from_slice(&0xDEADBEEFu32.to_le_bytes());
+        wrong.extend_from_slice(&crc32(&wrong).to_le_bytes());
+        assert_eq!(apply(&source, &wrong), Err(PatchError::TargetChecksum { expected: 0xDEADBEEF, actual: crc32(&target) }));
+    }
+
+    #[test]
+    fn ups_offsets_that_overflow() {
+        // a hunk at the very end of the address space, XORing past it
+        let source = [0x00; 4];
+        let mut body = varint(usize::MAX);
+        body.extend_from_slice(&[0x01, 0x00]);
+        let patch = patch(b"UPS1", &source, &source, &body);
+        assert_eq!(apply(&source, &patch), Err(PatchError::Malformed(PatchFormat::Ups)));
+    }
+
+    #[test]
+    fn bps_target_size_is_capped() {
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(4));
+        patch.extend(varint(usize::MAX >> 8));
+        patch.extend(varint(0));
+        patch.extend_from_slice(&[0x00; 8]);
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        assert_eq!(apply(&[0x00; 4], &patch), Err(PatchError::Malformed(PatchFormat::Bps)));
+    }
+
+    #[test]
+    fn bps_offsets_that_overflow() {
+        let source = [0x01, 0x02, 0x03, 0x04];
+        let target = [0x01, 0x02];
+        let actions: [&[u8]; 3] = [
+            // SourceCopy 1 from as far forward as can be stored
+            &[&[0x80, 0x80 | 2][..], &varint(usize::MAX - 1)].concat(),
+            // SourceCopy 1 from before the start
+            &[0x80, 0x80 | 2, 0x80 | 3],
+            // an action longer than memory
+            &[&[0x80][..], &varint(usize::MAX)].concat(),
+        ];
+        for body in actions {
+            let patch = patch(b"BPS1", &source, &target, body);
+            assert_eq!(apply(&source, &patch), Err(PatchError::Malformed(PatchFormat::Bps)));
+        }
+    }
+
+    #[test]
+    fn unknown_format() {
+        assert_eq!(apply(&[0x00; 4], b"NES\x1A"), Err(PatchError::UnknownFormat));
+    }
+}
@@ -1,7 +1,406 @@
+use crate::cartridge::{Cartridge, Mirroring};
+use crate::displays::display::{Pixel, ScreenBuffer};
+use crate::nes::Region;
+use crate::save_state::{SaveStateError, StateReader, StateWriter};
+
+// Bits of the I/O latch decay to 0 after roughly 600 ms without being refreshed
+const IO_LATCH_DECAY_FRAMES: u64 = 36;
+
+// RGB output for each of the 64 colours the 2C02 can produce
+// https://www.nesdev.org/wiki/PPU_palettes
+const SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84), (0, 30, 116), (8, 16, 144), (48, 0, 136), (68, 0, 100), (92, 0, 48), (84, 4, 0), (60, 24, 0),
+    (32, 42, 0), (8, 58, 0), (0, 64, 0), (0, 60, 0), (0, 50, 60), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (152, 150, 152), (8, 76, 196), (48, 50, 236), (92, 30, 228), (136, 20, 176), (160, 20, 100), (152, 34, 32), (120, 60, 0),
+    (84, 90, 0), (40, 114, 0), (8, 124, 0), (0, 118, 40), (0, 102, 120), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (76, 154, 236), (120, 124, 236), (176, 98, 236), (228, 84, 236), (236, 88, 180), (236, 106, 100), (212, 136, 32),
+    (160, 170, 0), (116, 196, 0), (76, 208, 32), (56, 204, 108), (56, 180, 204), (60, 60, 60), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236), (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+    (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180), (160, 214, 228), (160, 162, 160), (0, 0, 0), (0, 0, 0),
+];
+
 pub struct PPU {
+    // scanline -1 is the pre-render line, 0..=239 are visible and 241 starts vblank
+    pub scanline: i16,
+    // scanlines per frame, 312 on PAL
+    scanlines: i16,
+    pub cycle: u16,
+    pub frame_complete: bool,
+    // frames completed since power on
+    pub frame_count: u64,
+
+    // $2000 PPUCTRL, $2001 PPUMASK, $2002 PPUSTATUS, $2003 OAMADDR
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
 
+    // loopy registers: current and temporary VRAM address, fine X scroll and the
+    // first/second write toggle shared by $2005 and $2006
+    pub vram_addr: u16,
+    pub tram_addr: u16,
+    pub fine_x: u8,
+    pub write_latch: bool,
+    // buffered value returned by $2007 reads
+    pub data_buffer: u8,
+    // The PPU's own data bus to the CPU, holding the last value written to or read
+    // from a register. Write-only registers and undriven bits read back from it.
+    // https://www.nesdev.org/wiki/Open_bus_behavior#PPU_open_bus
+    io_latch: u8,
+    // frame on which each bit of the latch was last refreshed
+    io_latch_frames: [u64; 8],
+
+    // nametable RAM, sized for four-screen cartridges; others use the first 2 KiB
+    pub vram: Vec<u8>,
+    pub palette: [u8; 32],
+    pub oam: [u8; 256],
+
+    screen: Box<ScreenBuffer>,
 }
 
 impl PPU {
+    pub fn new() -> PPU {
+        let mut ppu = PPU {
+            scanline: 0,
+            scanlines: Region::Ntsc.scanlines(),
+            cycle: 0,
+            frame_complete: false,
+            frame_count: 0,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            vram_addr: 0,
+            tram_addr: 0,
+            fine_x: 0,
+            write_latch: false,
+            data_buffer: 0,
+            io_latch: 0,
+            io_latch_frames: [0; 8],
+            vram: vec![0x00; 4096],
+            palette: [0x00; 32],
+            oam: [0x00; 256],
+            screen: Box::new(ScreenBuffer::new()),
+        };
+        ppu.power_on();
+        return ppu;
+    }
+
+    // https://www.nesdev.org/wiki/PPU_power_up_state
+    pub fn power_on(&mut self) {
+        self.reset();
+        // vblank and sprite overflow are often set at power on
+        self.status = 0xA0;
+        self.oam_addr = 0;
+        self.vram_addr = 0;
+        self.frame_count = 0;
+        self.io_latch = 0;
+        self.io_latch_frames = [0; 8];
+    }
+
+    // PPUSTATUS, OAMADDR and the current VRAM address survive a reset
+    pub fn reset(&mut self) {
+        self.scanline = 0;
+        self.cycle = 0;
+        self.frame_complete = false;
+        self.ctrl = 0;
+        self.mask = 0;
+        self.tram_addr = 0;
+        self.fine_x = 0;
+        self.write_latch = false;
+        self.data_buffer = 0;
+    }
+
+    // the picture is not saved; it is redrawn within a frame of loading
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.i16(self.scanline);
+        state.u16(self.cycle);
+        state.bool(self.frame_complete);
+        state.u64(self.frame_count);
+        for value in [self.ctrl, self.mask, self.status, self.oam_addr, self.fine_x, self.data_buffer, self.io_latch] {
+            state.u8(value);
+        }
+        state.u16(self.vram_addr);
+        state.u16(self.tram_addr);
+        state.bool(self.write_latch);
+        for frame in self.io_latch_frames {
+            state.u64(frame);
+        }
+        state.bytes(&self.vram);
+        state.bytes(&self.palette);
+        state.bytes(&self.oam);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), SaveStateError> {
+        self.scanline = state.i16()?;
+        self.cycle = state.u16()?;
+        if !(-1..self.scanlines - 1).contains(&self.scanline) || self.cycle >= 341 {
+            return Err(SaveStateError::Invalid);
+        }
+        self.frame_complete = state.bool()?;
+        self.frame_count = state.u64()?;
+        for value in [&mut self.ctrl, &mut self.mask, &mut self.status, &mut self.oam_addr, &mut self.fine_x,
+                &mut self.data_buffer, &mut self.io_latch] {
+            *value = state.u8()?;
+        }
+        self.vram_addr = state.u16()?;
+        self.tram_addr = state.u16()?;
+        self.write_latch = state.bool()?;
+        for frame in self.io_latch_frames.iter_mut() {
+            *frame = state.u64()?;
+            if *frame > self.frame_count {
+                return Err(SaveStateError::Invalid);
+            }
+        }
+        state.bytes(&mut self.vram)?;
+        state.bytes(&mut self.palette)?;
+        state.bytes(&mut self.oam)?;
+        return Ok(());
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.scanlines = region.scanlines();
+    }
+
+    pub fn screen(&self) -> &ScreenBuffer {
+        return &self.screen;
+    }
+
+    // the NMI output: PPUSTATUS vblank gated by PPUCTRL bit 7
+    pub fn nmi(&self) -> bool {
+        return self.status & 0x80 > 0 && self.ctrl & 0x80 > 0;
+    }
+
+    pub fn clock(&mut self) {
+        if self.scanline == 241 && self.cycle == 1 {
+            self.status |= 0x80;
+        }
+        if self.scanline == -1 && self.cycle == 1 {
+            // vblank, sprite 0 hit and sprite overflow
+            self.status &= !0xE0;
+        }
+        if (0..240).contains(&self.scanline) && (1..=256).contains(&self.cycle) {
+            let pixel = self.backdrop_pixel();
+            self.screen.write_pixel(self.scanline as usize, self.cycle as usize - 1, pixel);
+        }
+
+        self.cycle += 1;
+        if self.cycle >= 341 {
+            self.cycle = 0;
+            self.scanline += 1;
+            if self.scanline >= self.scanlines - 1 {
+                self.scanline = -1;
+                self.frame_complete = true;
+                self.frame_count += 1;
+            }
+        }
+    }
+
+    // Only the backdrop colour is drawn so far; background tiles and sprites are
+    // not rendered yet. With rendering off and the VRAM address inside the palette,
+    // the PPU outputs the entry it points at instead.
+    fn backdrop_pixel(&self) -> Pixel {
+        let addr = self.vram_addr & 0x3FFF;
+        let index = if self.mask & 0x18 == 0 && addr >= 0x3F00 {
+            Self::palette_index(addr)
+        } else {
+            0
+        };
+        // PPUMASK bit 0 selects greyscale
+        let colour = self.palette[index] & if self.mask & 0x01 > 0 { 0x30 } else { 0x3F };
+        let (r, g, b) = SYSTEM_PALETTE[colour as usize];
+        return Pixel::new(r, g, b);
+    }
+
+    // CPU access to the registers at $2000-$2007, `addr` already reduced to 0..=7
+    // https://www.nesdev.org/wiki/PPU_registers
+
+    pub fn cpu_read(&mut self, addr: u16, cartridge: &mut Cartridge) -> u8 {
+        let latch = self.io_latch();
+        // the value read and which of its bits the PPU actually drove
+        let (data, driven) = match addr {
+            // PPUSTATUS: only the top three bits are driven
+            0x0002 => {
+                let data = (self.status & 0xE0) | (latch & 0x1F);
+                self.status &= !0x80;
+                self.write_latch = false;
+                (data, 0xE0)
+            },
+            0x0004 => (self.oam[self.oam_addr as usize], 0xFF),
+            0x0007 => {
+                // reads are delayed by one through the buffer, except for the palette
+                // which is returned at once while the nametable beneath it is buffered
+                let addr = self.vram_addr & 0x3FFF;
+                let mut data = self.data_buffer;
+                let mut driven = 0xFF;
+                self.data_buffer = self.ppu_read(addr, cartridge);
+                if addr >= 0x3F00 {
+                    // palette entries are 6 bits wide
+                    data = (self.data_buffer & 0x3F) | (latch & 0xC0);
+                    driven = 0x3F;
+                    self.data_buffer = self.ppu_read(addr - 0x1000, cartridge);
+                }
+                self.increment_vram_addr();
+                (data, driven)
+            },
+            // write-only
+            _ => (latch, 0x00),
+        };
+        self.refresh_io_latch(data, driven);
+        return data;
+    }
+
+    // what cpu_read would return, without clearing vblank or the write latch and
+    // without moving the VRAM address
+    pub fn cpu_peek(&self, addr: u16, cartridge: &Cartridge) -> u8 {
+        let latch = self.io_latch();
+        return match addr {
+            0x0002 => (self.status & 0xE0) | (latch & 0x1F),
+            0x0004 => self.oam[self.oam_addr as usize],
+            0x0007 => {
+                let addr = self.vram_addr & 0x3FFF;
+                if addr >= 0x3F00 {
+                    (self.ppu_peek(addr, cartridge) & 0x3F) | (latch & 0xC0)
+                } else {
+                    self.data_buffer
+                }
+            },
+            _ => latch,
+        };
+    }
+
+    // the I/O latch with decayed bits cleared
+    fn io_latch(&self) -> u8 {
+        let mut latch = self.io_latch;
+        for (bit, frame) in self.io_latch_frames.iter().enumerate() {
+            if self.frame_count - frame > IO_LATCH_DECAY_FRAMES {
+                latch &= !(1 << bit);
+            }
+        }
+        return latch;
+    }
+
+    fn refresh_io_latch(&mut self, data: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (data & mask);
+        for (bit, frame) in self.io_latch_frames.iter_mut().enumerate() {
+            if mask & (1 << bit) > 0 {
+                *frame = self.frame_count;
+            }
+        }
+    }
+
+    pub fn cpu_write(&mut self, addr: u16, data: u8, cartridge: &mut Cartridge) {
+        // every write, even to PPUSTATUS, goes through the latch
+        self.refresh_io_latch(data, 0xFF);
+        match addr {
+            0x0000 => {
+                self.ctrl = data;
+                self.tram_addr = (self.tram_addr & 0xF3FF) | ((data as u16 & 0x03) << 10);
+            },
+            0x0001 => self.mask = data,
+            0x0003 => self.oam_addr = data,
+            0x0004 => {
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            },
+            0x0005 => {
+                if !self.write_latch {
+                    self.fine_x = data & 0x07;
+                    self.tram_addr = (self.tram_addr & 0xFFE0) | (data as u16 >> 3);
+                } else {
+                    self.tram_addr = (self.tram_addr & 0x8C1F)
+                        | ((data as u16 & 0x07) << 12) | ((data as u16 & 0xF8) << 2);
+                }
+                self.write_latch = !self.write_latch;
+            },
+            0x0006 => {
+                if !self.write_latch {
+                    self.tram_addr = (self.tram_addr & 0x00FF) | ((data as u16 & 0x3F) << 8);
+                } else {
+                    self.tram_addr = (self.tram_addr & 0xFF00) | data as u16;
+                    self.vram_addr = self.tram_addr;
+                }
+                self.write_latch = !self.write_latch;
+            },
+            0x0007 => {
+                self.ppu_write(self.vram_addr & 0x3FFF, data, cartridge);
+                self.increment_vram_addr();
+            },
+            // PPUSTATUS is read-only
+            _ => {},
+        }
+    }
+
+    // PPUCTRL bit 2 selects going across (1) or down (32) a nametable
+    fn increment_vram_addr(&mut self) {
+        let step = if self.ctrl & 0x04 > 0 { 32 } else { 1 };
+        self.vram_addr = self.vram_addr.wrapping_add(step) & 0x7FFF;
+    }
+
+    // PPU address space: pattern tables on the cartridge, then nametables and palette
+    // https://www.nesdev.org/wiki/PPU_memory_map
+
+    pub fn ppu_read(&mut self, addr: u16, cartridge: &mut Cartridge) -> u8 {
+        let addr = addr & 0x3FFF;
+        if let Some(data) = cartridge.ppu_read(addr) {
+            return data;
+        }
+        return self.internal_read(addr, cartridge.mirroring());
+    }
+
+    pub fn ppu_peek(&self, addr: u16, cartridge: &Cartridge) -> u8 {
+        let addr = addr & 0x3FFF;
+        if let Some(data) = cartridge.ppu_peek(addr) {
+            return data;
+        }
+        return self.internal_read(addr, cartridge.mirroring());
+    }
+
+    // nametables and palette, for addresses the cartridge does not claim
+    fn internal_read(&self, addr: u16, mirroring: Mirroring) -> u8 {
+        return match addr {
+            0x0000..=0x1FFF => 0x00,
+            0x2000..=0x3EFF => self.vram[Self::nametable_index(addr, mirroring)],
+            _ => self.palette[Self::palette_index(addr)],
+        };
+    }
+
+    pub fn ppu_write(&mut self, addr: u16, data: u8, cartridge: &mut Cartridge) {
+        let addr = addr & 0x3FFF;
+        if cartridge.ppu_write(addr, data) {
+            return;
+        }
+        match addr {
+            0x0000..=0x1FFF => {},
+            0x2000..=0x3EFF => self.vram[Self::nametable_index(addr, cartridge.mirroring())] = data,
+            _ => self.palette[Self::palette_index(addr)] = data,
+        }
+    }
+
+    // $3000-$3EFF mirrors $2000-$2EFF
+    fn nametable_index(addr: u16, mirroring: Mirroring) -> usize {
+        let table = (addr as usize & 0x0FFF) / 0x0400;
+        let offset = addr as usize & 0x03FF;
+        let physical = match mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 1,
+            Mirroring::FourScreen => table,
+        };
+        return physical * 0x0400 + offset;
+    }
+
+    // $3F10, $3F14, $3F18 and $3F1C mirror the backdrop entries below them
+    fn palette_index(addr: u16) -> usize {
+        let index = addr as usize & 0x001F;
+        if index & 0x13 == 0x10 {
+            return index & 0x0F;
+        }
+        return index;
+    }
+}
 
+impl Default for PPU {
+    fn default() -> PPU {
+        return PPU::new();
+    }
 }
@@ -0,0 +1,202 @@
+// Database of known games, looked up by the checksum of their PRG and CHR ROM to
+// correct dumps whose iNES header gives the wrong mapper, mirroring or RAM
+
+use std::error;
+use std::fmt;
+use std::sync::OnceLock;
+use crate::cartridge::{Header, Mirroring, Timing};
+
+// see the file for its format
+const EMBEDDED: &str = include_str!("../data/nes20db.txt");
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct GameInfo {
+    // of PRG ROM followed by CHR ROM
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub title: String,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+}
+
+impl GameInfo {
+    // the header with every field the database knows replaced
+    pub fn correct(&self, header: &Header) -> Header {
+        let mut corrected = *header;
+        corrected.mapper = self.mapper;
+        corrected.submapper = self.submapper;
+        corrected.mirroring = self.mirroring;
+        corrected.prg_ram_size = self.prg_ram_size;
+        corrected.prg_nvram_size = self.prg_nvram_size;
+        corrected.chr_ram_size = self.chr_ram_size;
+        corrected.chr_nvram_size = self.chr_nvram_size;
+        corrected.battery = self.prg_nvram_size > 0 || self.chr_nvram_size > 0;
+        corrected.timing = self.timing;
+        corrected.region = self.timing.region();
+        return corrected;
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DatabaseError {
+    // 1-based
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "ROM database line {}: {}", self.line, self.message);
+    }
+}
+
+impl error::Error for DatabaseError {}
+
+pub struct RomDatabase {
+    games: Vec<GameInfo>,
+}
+
+impl RomDatabase {
+    // the database built into the emulator
+    pub fn embedded() -> &'static RomDatabase {
+        static DATABASE: OnceLock<RomDatabase> = OnceLock::new();
+        return DATABASE.get_or_init(|| {
+            RomDatabase::parse(EMBEDDED).expect("the embedded ROM database should be valid")
+        });
+    }
+
+    pub fn parse(text: &str) -> Result<RomDatabase, DatabaseError> {
+        let mut games = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let game = parse_line(line).map_err(|message| DatabaseError { line: i + 1, message })?;
+            games.push(game);
+        }
+        return Ok(RomDatabase { games });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.games.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.games.is_empty();
+    }
+
+    // Finds the game whose PRG and CHR ROM have these checksums. The SHA-1 is only
+    // compared for entries that list one.
+    pub fn find(&self, crc32: u32, sha1: &[u8; 20]) -> Option<&GameInfo> {
+        return self.games.iter()
+            .find(|game| game.crc32 == crc32 && game.sha1.is_none_or(|s| s == *sha1));
+    }
+}
+
+// takes the next whitespace-separated field off the front of `rest`
+fn field<'a>(rest: &mut &'a str, name: &str) -> Result<&'a str, String> {
+    let trimmed = rest.trim_start();
+    if trimmed.is_empty() {
+        return Err(format!("missing {}", name));
+    }
+    let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+    *rest = &trimmed[end..];
+    return Ok(&trimmed[..end]);
+}
+
+fn parse_line(line: &str) -> Result<GameInfo, String> {
+    let mut rest = line;
+
+    let crc32 = u32::from_str_radix(field(&mut rest, "crc32")?, 16).map_err(|e| format!("crc32: {}", e))?;
+    let sha1 = match field(&mut rest, "sha1")? {
+        "-" => None,
+        hex => Some(parse_sha1(hex)?),
+    };
+    let mapper = parse_number(field(&mut rest, "mapper")?, "mapper")?;
+    let submapper = parse_number(field(&mut rest, "submapper")?, "submapper")?;
+    let mirroring = match field(&mut rest, "mirroring")? {
+        "H" => Mirroring::Horizontal,
+        "V" => Mirroring::Vertical,
+        "4" => Mirroring::FourScreen,
+        other => return Err(format!("unknown mirroring {}", other)),
+    };
+    let prg_ram_size = parse_number(field(&mut rest, "prg_ram")?, "prg_ram")?;
+    let prg_nvram_size = parse_number(field(&mut rest, "prg_nvram")?, "prg_nvram")?;
+    let chr_ram_size = parse_number(field(&mut rest, "chr_ram")?, "chr_ram")?;
+    let chr_nvram_size = parse_number(field(&mut rest, "chr_nvram")?, "chr_nvram")?;
+    let timing = match field(&mut rest, "timing")? {
+        "ntsc" => Timing::Ntsc,
+        "pal" => Timing::Pal,
+        "multi" => Timing::MultiRegion,
+        "dendy" => Timing::Dendy,
+        other => return Err(format!("unknown timing {}", other)),
+    };
+    let title = rest.trim().to_string();
+    if title.is_empty() {
+        return Err("missing title".to_string());
+    }
+
+    return Ok(GameInfo {
+        crc32,
+        sha1,
+        title,
+        mapper,
+        submapper,
+        mirroring,
+        prg_ram_size,
+        prg_nvram_size,
+        chr_ram_size,
+        chr_nvram_size,
+        timing,
+    });
+}
+
+fn parse_number<T: std::str::FromStr>(field: &str, name: &str) -> Result<T, String> {
+    return field.parse().map_err(|_| format!("invalid {} {}", name, field));
+}
+
+fn parse_sha1(hex: &str) -> Result<[u8; 20], String> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return Err(format!("invalid sha1 {}", hex));
+    }
+    let mut digest = [0x00; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| format!("invalid sha1 {}", hex))?;
+    }
+    return Ok(digest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an iNES header for NROM with horizontal mirroring and 8 KiB of PRG RAM
+    fn nrom_header() -> Header {
+        return Header::parse(&[b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    }
+
+    #[test]
+    fn embedded_database_corrects_its_games() {
+        let database = RomDatabase::parse(EMBEDDED).unwrap();
+        for game in &database.games {
+            let found = database.find(game.crc32, &game.sha1.unwrap_or([0x00; 20])).unwrap();
+            assert_eq!(found.crc32, game.crc32);
+            let header = game.correct(&nrom_header());
+            assert_eq!((header.mapper, header.submapper, header.mirroring), (game.mapper, game.submapper, game.mirroring));
+            assert_eq!(header.region, game.timing.region());
+        }
+    }
+
+    #[test]
+    #[ignore = "data/nes20db.txt holds no games until it is generated from nes20db.xml"]
+    fn embedded_database_has_games() {
+        assert!(!RomDatabase::embedded().is_empty());
+    }
+}
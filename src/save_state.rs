@@ -0,0 +1,179 @@
+// Save states: a snapshot of the whole console that can be restored later. Each
+// component writes its fields in a fixed order after a short header, and a state
+// from a build with a different layout is rejected rather than misread.
+
+use std::error;
+use std::fmt;
+
+const MAGIC: [u8; 4] = *b"NESS";
+// bump whenever any component changes what it saves
+const VERSION: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SaveStateError {
+    // not a save state, or one from an incompatible version
+    BadHeader,
+    // the data ended early
+    Truncated,
+    // taken on a different region or cartridge than the one running
+    Mismatch,
+    // a field holds a value the console could never be in
+    Invalid,
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            SaveStateError::BadHeader => "not a save state for this version",
+            SaveStateError::Truncated => "save state is truncated",
+            SaveStateError::Mismatch => "save state is for a different region or cartridge",
+            SaveStateError::Invalid => "save state is corrupt",
+        };
+        return write!(f, "{}", message);
+    }
+}
+
+impl error::Error for SaveStateError {}
+
+// Passed to each component, and to mappers through Mapper::save_state
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub(crate) fn new() -> StateWriter {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        return StateWriter { data };
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i16(&mut self, value: i16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn option_u8(&mut self, value: Option<u8>) {
+        self.bool(value.is_some());
+        self.u8(value.unwrap_or(0));
+    }
+
+    pub fn option_u16(&mut self, value: Option<u16>) {
+        self.bool(value.is_some());
+        self.u16(value.unwrap_or(0));
+    }
+
+    // prefixed with the length, so memories of a different size are caught on load
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        self.data.extend_from_slice(value);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        return self.data;
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Result<StateReader<'a>, SaveStateError> {
+        if data.len() < 5 || data[..4] != MAGIC || data[4] != VERSION {
+            return Err(SaveStateError::BadHeader);
+        }
+        return Ok(StateReader { data, pos: 5 });
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() - self.pos < len {
+            return Err(SaveStateError::Truncated);
+        }
+        let slice = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        return Ok(slice);
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SaveStateError> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    pub fn u16(&mut self) -> Result<u16, SaveStateError> {
+        let bytes = self.take(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SaveStateError> {
+        let bytes = self.take(4)?;
+        return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SaveStateError> {
+        let mut bytes = [0x00; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+
+    pub fn i16(&mut self) -> Result<i16, SaveStateError> {
+        let bytes = self.take(2)?;
+        return Ok(i16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SaveStateError> {
+        return match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SaveStateError::Invalid),
+        };
+    }
+
+    pub fn option_u8(&mut self) -> Result<Option<u8>, SaveStateError> {
+        let some = self.bool()?;
+        let value = self.u8()?;
+        return Ok(if some { Some(value) } else { None });
+    }
+
+    pub fn option_u16(&mut self) -> Result<Option<u16>, SaveStateError> {
+        let some = self.bool()?;
+        let value = self.u16()?;
+        return Ok(if some { Some(value) } else { None });
+    }
+
+    // fills `dest`, which must be the size the memory was saved at
+    pub fn bytes(&mut self, dest: &mut [u8]) -> Result<(), SaveStateError> {
+        let len = self.u32()? as usize;
+        if len != dest.len() {
+            return Err(SaveStateError::Mismatch);
+        }
+        dest.copy_from_slice(self.take(len)?);
+        return Ok(());
+    }
+
+    // trailing data means the state was not written by this version
+    pub(crate) fn finish(self) -> Result<(), SaveStateError> {
+        if self.pos != self.data.len() {
+            return Err(SaveStateError::Invalid);
+        }
+        return Ok(());
+    }
+}
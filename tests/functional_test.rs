@@ -0,0 +1,39 @@
+// Runs Klaus Dormann's 6502 functional test to its success trap. The binary is not in
+// the repository; put 6502_functional_test.bin, assembled with the default options, in
+// tests/data to run the test.
+// https://github.com/Klaus2m5/6502_65C02_functional_tests
+
+use std::fs;
+use std::path::Path;
+use emulator::cpu::{CPU, StopReason, Variant};
+use emulator::memory::FlatMemory;
+
+// where the default build starts, and the JMP * it reaches once every test passes
+const START: u16 = 0x0400;
+const SUCCESS: u16 = 0x3469;
+
+#[test]
+fn functional_test() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/6502_functional_test.bin");
+    if !path.is_file() {
+        println!("skipping functional test: {} not found", path.display());
+        return;
+    }
+
+    let mut memory = FlatMemory::new();
+    memory.load(0x0000, &fs::read(&path).unwrap());
+    // the default build checks decimal mode, which the 2A03 does not have
+    let mut cpu = CPU::with_variant(memory, Variant::Nmos6502);
+    cpu.pc = START;
+    cpu.sp = 0xFD;
+
+    // every failure is a branch or jump to itself, so stop at the first one
+    let mut prev = START;
+    let reason = cpu.run_until(200_000_000, |cpu| {
+        let trapped = cpu.pc == prev;
+        prev = cpu.pc;
+        trapped
+    });
+    assert_eq!(reason, StopReason::Done);
+    assert_eq!(cpu.pc, SUCCESS, "trapped at ${:04X}, test case {}", cpu.pc, cpu.bus.ram[0x0200]);
+}
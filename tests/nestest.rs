@@ -0,0 +1,68 @@
+// Runs nestest.nes in automation mode, jumping straight to $C000 so no display is
+// needed, and compares the trace against the golden nestest.log line by line. The
+// fixtures are not in the repository; put them in tests/data to run the test.
+// https://www.nesdev.org/wiki/Emulator_tests
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use emulator::cpu::StopReason;
+use emulator::{Cartridge, Nes};
+
+// a tracer sink the test can read back while the CPU owns it
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(data);
+        return Ok(data.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl SharedBuffer {
+    fn take_lines(&self) -> Vec<String> {
+        let data = std::mem::take(&mut *self.0.borrow_mut());
+        return String::from_utf8(data).unwrap().lines().map(str::to_string).collect();
+    }
+}
+
+// powers on a console with the cartridge and points the CPU at $C000 once the reset
+// sequence has run, as automation mode expects
+fn automation_mode(cartridge: Cartridge) -> (Nes, SharedBuffer) {
+    let mut nes = Nes::new(cartridge);
+    assert_eq!(nes.cpu.step_instruction(), StopReason::Done);
+    nes.cpu.pc = 0xC000;
+    let trace = SharedBuffer::default();
+    nes.cpu.set_tracer(Box::new(trace.clone()));
+    return (nes, trace);
+}
+
+#[test]
+fn nestest() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let (rom, log) = (dir.join("nestest.nes"), dir.join("nestest.log"));
+    if !rom.is_file() || !log.is_file() {
+        println!("skipping nestest: nestest.nes and nestest.log not found in {}", dir.display());
+        return;
+    }
+
+    let expected = fs::read_to_string(&log).unwrap();
+    let expected: Vec<&str> = expected.lines().collect();
+    let (mut nes, trace) = automation_mode(Cartridge::new(&rom).unwrap());
+    for (i, want) in expected.iter().enumerate() {
+        nes.cpu.step_instruction();
+        let got = trace.take_lines();
+        assert_eq!(got.len(), 1, "line {}: expected one instruction to be traced", i + 1);
+        assert_eq!(got[0], want.trim_end(), "line {} of nestest.log differs", i + 1);
+    }
+
+    // the official and unofficial opcode results end up in $02 and $03
+    assert_eq!(nes.bus().cpu_ram[0x02..0x04], [0x00, 0x00]);
+}
@@ -0,0 +1,274 @@
+// Runs the SingleStepTests/ProcessorTests 65x02 suite. Each opcode has a JSON file
+// (00.json .. ff.json) of cases giving the CPU state and memory before and after one
+// instruction, plus every bus access made on each cycle. The data is not in the
+// repository; put the suite's 65x02 directory in tests/data to run these tests.
+// https://github.com/SingleStepTests/65x02
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use emulator::cpu::{CPU, Variant};
+use emulator::memory::Memory;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum BusAccess {
+    Read,
+    Write,
+}
+
+// one bus cycle: (address, value, access)
+type BusCycle = (u16, u8, BusAccess);
+
+// 64 KiB of RAM that records every access made by the CPU
+struct RecordingMemory {
+    ram: Vec<u8>,
+    cycles: Vec<BusCycle>,
+}
+
+impl RecordingMemory {
+    fn new() -> RecordingMemory {
+        return RecordingMemory {
+            ram: vec![0x00; 0x10000],
+            cycles: vec![],
+        }
+    }
+}
+
+impl Memory for RecordingMemory {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = self.ram[addr as usize];
+        self.cycles.push((addr, data, BusAccess::Read));
+        return data;
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.ram[addr as usize] = data;
+        self.cycles.push((addr, data, BusAccess::Write));
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        return self.ram[addr as usize];
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ProcessorState {
+    pc: u16,
+    sp: u8,
+    a: u8,
+    x: u8,
+    y: u8,
+    p: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+#[derive(Clone, Debug)]
+struct TestCase {
+    name: String,
+    initial: ProcessorState,
+    expected: ProcessorState,
+    cycles: Vec<BusCycle>,
+}
+
+#[derive(Clone, Debug)]
+struct OpcodeReport {
+    opcode: u8,
+    cases: usize,
+    // one line per failed case, naming the first mismatches found
+    failures: Vec<String>,
+}
+
+impl OpcodeReport {
+    fn passed(&self) -> bool {
+        return self.failures.is_empty();
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+fn field(value: &Value, name: &str) -> io::Result<u64> {
+    return value[name].as_u64()
+        .ok_or_else(|| invalid_data(format!("missing or invalid field \"{}\"", name)));
+}
+
+fn parse_state(value: &Value) -> io::Result<ProcessorState> {
+    let mut ram = vec![];
+    let entries = value["ram"].as_array()
+        .ok_or_else(|| invalid_data("missing ram".to_string()))?;
+    for entry in entries {
+        match (entry[0].as_u64(), entry[1].as_u64()) {
+            (Some(addr), Some(data)) => ram.push((addr as u16, data as u8)),
+            _ => return Err(invalid_data(format!("invalid ram entry {}", entry))),
+        }
+    }
+
+    return Ok(ProcessorState {
+        pc: field(value, "pc")? as u16,
+        sp: field(value, "s")? as u8,
+        a: field(value, "a")? as u8,
+        x: field(value, "x")? as u8,
+        y: field(value, "y")? as u8,
+        p: field(value, "p")? as u8,
+        ram,
+    });
+}
+
+fn parse_case(value: &Value) -> io::Result<TestCase> {
+    let mut cycles = vec![];
+    let entries = value["cycles"].as_array()
+        .ok_or_else(|| invalid_data("missing cycles".to_string()))?;
+    for entry in entries {
+        let access = match entry[2].as_str() {
+            Some("read") => BusAccess::Read,
+            Some("write") => BusAccess::Write,
+            _ => return Err(invalid_data(format!("invalid cycle {}", entry))),
+        };
+        match (entry[0].as_u64(), entry[1].as_u64()) {
+            (Some(addr), Some(data)) => cycles.push((addr as u16, data as u8, access)),
+            _ => return Err(invalid_data(format!("invalid cycle {}", entry))),
+        }
+    }
+
+    return Ok(TestCase {
+        name: value["name"].as_str().unwrap_or("").to_string(),
+        initial: parse_state(&value["initial"])?,
+        expected: parse_state(&value["final"])?,
+        cycles,
+    });
+}
+
+fn load_cases(path: &Path) -> io::Result<Vec<TestCase>> {
+    let text = fs::read_to_string(path)?;
+    let json: Value = serde_json::from_str(&text)
+        .map_err(|e| invalid_data(format!("{}: {}", path.display(), e)))?;
+    let cases = json.as_array()
+        .ok_or_else(|| invalid_data(format!("{}: expected an array of cases", path.display())))?;
+    return cases.iter().map(parse_case).collect();
+}
+
+// Runs a single case, returning a description of every mismatch
+fn run_case(case: &TestCase, variant: Variant) -> Vec<String> {
+    let mut memory = RecordingMemory::new();
+    for &(addr, data) in &case.initial.ram {
+        memory.ram[addr as usize] = data;
+    }
+
+    let mut cpu = CPU::with_variant(memory, variant);
+    cpu.pc = case.initial.pc;
+    cpu.sp = case.initial.sp;
+    cpu.a = case.initial.a;
+    cpu.x = case.initial.x;
+    cpu.y = case.initial.y;
+    cpu.status = case.initial.p;
+
+    // JAM never completes, so stop once the expected number of cycles has run
+    let mut ran = 0;
+    loop {
+        cpu.clock();
+        ran += 1;
+        if cpu.complete() || ran >= case.cycles.len().max(1) {
+            break;
+        }
+    }
+
+    let mut mismatches = vec![];
+    let expected = &case.expected;
+    let registers = [
+        ("PC", cpu.pc, expected.pc),
+        ("SP", cpu.sp as u16, expected.sp as u16),
+        ("A", cpu.a as u16, expected.a as u16),
+        ("X", cpu.x as u16, expected.x as u16),
+        ("Y", cpu.y as u16, expected.y as u16),
+        ("P", cpu.status as u16, expected.p as u16),
+    ];
+    for (name, got, want) in registers.iter() {
+        if got != want {
+            mismatches.push(format!("{} is ${:02X}, expected ${:02X}", name, got, want));
+        }
+    }
+
+    for &(addr, want) in &expected.ram {
+        let got = cpu.bus.ram[addr as usize];
+        if got != want {
+            mismatches.push(format!("${:04X} is ${:02X}, expected ${:02X}", addr, got, want));
+        }
+    }
+
+    let bus = &cpu.bus.cycles;
+    if bus.len() != case.cycles.len() {
+        mismatches.push(format!("took {} cycles, expected {}", bus.len(), case.cycles.len()));
+    }
+    for (i, (got, want)) in bus.iter().zip(case.cycles.iter()).enumerate() {
+        if got != want {
+            mismatches.push(format!("cycle {}: {:?} ${:04X} = ${:02X}, expected {:?} ${:04X} = ${:02X}",
+                i + 1, got.2, got.0, got.1, want.2, want.0, want.1));
+        }
+    }
+    return mismatches;
+}
+
+fn run_opcode(opcode: u8, cases: &[TestCase], variant: Variant) -> OpcodeReport {
+    let mut failures = vec![];
+    for case in cases {
+        let mismatches = run_case(case, variant);
+        if !mismatches.is_empty() {
+            failures.push(format!("{}: {}", case.name, mismatches.join(", ")));
+        }
+    }
+    return OpcodeReport {
+        opcode,
+        cases: cases.len(),
+        failures,
+    };
+}
+
+// Runs every opcode file found in `dir`; opcodes without a file are left out
+fn run_suite(dir: &Path, variant: Variant) -> io::Result<Vec<OpcodeReport>> {
+    let mut reports = vec![];
+    for opcode in 0x00..=0xFF {
+        let path = dir.join(format!("{:02x}.json", opcode));
+        if !path.is_file() {
+            continue;
+        }
+        reports.push(run_opcode(opcode, &load_cases(&path)?, variant));
+    }
+    return Ok(reports);
+}
+
+// Runs the suite for one chip, which is skipped when its data is absent
+fn check(name: &str, variant: Variant) {
+    let dir: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests/data/65x02", name, "v1"].iter().collect();
+    if !dir.is_dir() {
+        println!("skipping processor tests: {} not found", dir.display());
+        return;
+    }
+
+    let reports = run_suite(&dir, variant).unwrap();
+    let mut failed = vec![];
+    for report in reports.iter().filter(|r| !r.passed()) {
+        failed.push(format!("{:02X}: {} of {} cases failed", report.opcode, report.failures.len(), report.cases));
+        for failure in report.failures.iter().take(5) {
+            failed.push(format!("    {}", failure));
+        }
+    }
+    assert!(failed.is_empty(), "{} opcodes run\n{}", reports.len(), failed.join("\n"));
+}
+
+// the NES CPU, with decimal mode disconnected
+#[test]
+fn nes6502() {
+    check("nes6502", Variant::Ricoh2A03);
+}
+
+#[test]
+fn nmos6502() {
+    check("6502", Variant::Nmos6502);
+}
+
+#[test]
+fn wdc65c02() {
+    check("wdc65c02", Variant::Cmos65C02);
+}